@@ -0,0 +1,38 @@
+//! Compares the time needed to scan the whole bus with `I2c::scan` and `I2c::scan_fast`. Works
+//! with any peripherals on the bus, or none.
+//!
+//! ```
+//! $ cargo run --example bench-scan
+//! ```
+
+use std::time::Instant;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+const ITERATIONS: u32 = 5;
+
+pub fn main() -> Result<()> {
+    let mut bus = i2c_tiny_usb::I2c::open_single_device()?;
+
+    for fast in [false, true] {
+        let start = Instant::now();
+        let mut found = Vec::new();
+        for _ in 0..ITERATIONS {
+            found = if fast {
+                bus.scan_fast(0x03..=0x77)?
+            } else {
+                bus.scan(0x03..=0x77)?
+            };
+        }
+        let per_scan = start.elapsed() / ITERATIONS;
+        let name = if fast { "scan_fast" } else { "scan" };
+        println!(
+            "{}: {:.2}ms per scan, found {:02x?}",
+            name,
+            per_scan.as_secs_f64() * 1000.0,
+            found
+        );
+    }
+
+    Ok(())
+}
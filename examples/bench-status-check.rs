@@ -0,0 +1,35 @@
+//! Compares the speed of short transfers with and without the status check after each message,
+//! see `I2c::set_status_check`. Requires an EEPROM or another peripheral at I2C address 0x50.
+//!
+//! ```
+//! $ cargo run --example bench-status-check
+//! ```
+
+use std::time::Instant;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+const I2C_ADDR: u16 = 0x50;
+const ITERATIONS: u32 = 200;
+
+pub fn main() -> Result<()> {
+    let mut bus = i2c_tiny_usb::I2c::open_single_device()?;
+
+    for enabled in [true, false] {
+        bus.set_status_check(enabled);
+        let mut buf = [0u8; 1];
+        let start = Instant::now();
+        for _ in 0..ITERATIONS {
+            bus.write_read(I2C_ADDR, &[0x00], &mut buf)?;
+        }
+        let per_transfer = start.elapsed() / ITERATIONS;
+        let state = if enabled { "enabled" } else { "disabled" };
+        println!(
+            "status check {}: {:.2}ms per transfer",
+            state,
+            per_transfer.as_secs_f64() * 1000.0
+        );
+    }
+
+    Ok(())
+}
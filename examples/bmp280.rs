@@ -0,0 +1,127 @@
+//! Reads temperature and pressure using a Bosch BMP280 sensor.
+//!
+//! ```
+//! $ cargo run --example bmp280
+//! T = 22.37°C
+//! p = 1008.62hPa
+//! ```
+
+use i2c::Address;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+const I2C_ADDR: u16 = 0x76; // 0x77 if SDO is pulled high
+const CHIP_ID: u8 = 0x58;
+
+const REG_CALIB: u8 = 0x88;
+const REG_ID: u8 = 0xd0;
+const REG_RESET: u8 = 0xe0;
+const REG_STATUS: u8 = 0xf3;
+const REG_CTRL_MEAS: u8 = 0xf4;
+const REG_PRESS_MSB: u8 = 0xf7;
+
+const RESET_WORD: u8 = 0xb6;
+const STATUS_MEASURING: u8 = 0x08;
+// temperature oversampling x1, pressure oversampling x1, forced mode
+const CTRL_MEAS_FORCED_X1: u8 = (0b001 << 5) | (0b001 << 2) | 0b01;
+
+/// Calibration coefficients stored in the sensor's non-volatile memory.
+struct Calibration {
+    t1: f64,
+    t2: f64,
+    t3: f64,
+    p: [f64; 9],
+}
+
+impl Calibration {
+    fn from_registers(regs: &[u8; 24]) -> Self {
+        let unsigned = |i: usize| u16::from_le_bytes([regs[2 * i], regs[2 * i + 1]]) as f64;
+        let signed = |i: usize| i16::from_le_bytes([regs[2 * i], regs[2 * i + 1]]) as f64;
+        let mut p = [0.0; 9];
+        p[0] = unsigned(3);
+        for (i, value) in p.iter_mut().enumerate().skip(1) {
+            *value = signed(3 + i);
+        }
+        Calibration {
+            t1: unsigned(0),
+            t2: signed(1),
+            t3: signed(2),
+            p,
+        }
+    }
+
+    /// Returns the temperature in °C and the "fine" temperature used to compensate the pressure,
+    /// following the floating point formulas from the datasheet.
+    fn temperature(&self, adc_t: f64) -> (f64, f64) {
+        let var1 = (adc_t / 16384.0 - self.t1 / 1024.0) * self.t2;
+        let var2 = (adc_t / 131072.0 - self.t1 / 8192.0).powi(2) * self.t3;
+        let t_fine = var1 + var2;
+        (t_fine / 5120.0, t_fine)
+    }
+
+    /// Returns the pressure in Pa.
+    fn pressure(&self, adc_p: f64, t_fine: f64) -> f64 {
+        let p = &self.p;
+        let mut var1 = t_fine / 2.0 - 64000.0;
+        let mut var2 = var1 * var1 * p[5] / 32768.0;
+        var2 += var1 * p[4] * 2.0;
+        var2 = var2 / 4.0 + p[3] * 65536.0;
+        var1 = (p[2] * var1 * var1 / 524288.0 + p[1] * var1) / 524288.0;
+        var1 = (1.0 + var1 / 32768.0) * p[0];
+        if var1 == 0.0 {
+            return 0.0; // avoid division by zero
+        }
+        let mut pressure = 1048576.0 - adc_p;
+        pressure = (pressure - var2 / 4096.0) * 6250.0 / var1;
+        var1 = p[8] * pressure * pressure / 2147483648.0;
+        var2 = pressure * p[7] / 32768.0;
+        pressure + (var1 + var2 + p[6]) / 16.0
+    }
+}
+
+/// Assembles a 20-bit measurement from its MSB, LSB and XLSB registers.
+fn raw_value(regs: &[u8]) -> f64 {
+    (((regs[0] as u32) << 12) | ((regs[1] as u32) << 4) | ((regs[2] as u32) >> 4)) as f64
+}
+
+pub fn main() -> Result<()> {
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    let mut bus = i2c_tiny_usb::I2c::open_single_device()?;
+    bus.set_slave_address(I2C_ADDR, false)?;
+
+    let id = bus.read_reg_u8(REG_ID)?;
+    if id != CHIP_ID {
+        return Err(format!("unexpected chip ID {id:#04x}, not a BMP280?").into());
+    }
+
+    bus.write_reg_u8(REG_RESET, RESET_WORD)?;
+    sleep(Duration::from_millis(10)); // 2ms per datasheet
+
+    // the whole calibration block is read in one burst
+    let mut calib = [0u8; 24];
+    bus.smbus_read_regs(REG_CALIB, &mut calib)?;
+    let calib = Calibration::from_registers(&calib);
+
+    // trigger a single measurement and wait for it to finish
+    bus.write_reg_u8(REG_CTRL_MEAS, CTRL_MEAS_FORCED_X1)?;
+    loop {
+        sleep(Duration::from_millis(10)); // up to 6.4ms per datasheet
+        if bus.read_reg_u8(REG_STATUS)? & STATUS_MEASURING == 0 {
+            break;
+        }
+    }
+
+    // pressure and temperature are read in a single burst so that they come from the same
+    // measurement
+    let mut data = [0u8; 6];
+    bus.smbus_read_regs(REG_PRESS_MSB, &mut data)?;
+    let (temp, t_fine) = calib.temperature(raw_value(&data[3..6]));
+    let pressure = calib.pressure(raw_value(&data[0..3]), t_fine);
+
+    println!("T = {:.2}°C", temp);
+    println!("p = {:.2}hPa", pressure / 100.0);
+
+    Ok(())
+}
@@ -14,7 +14,7 @@
 //! 70: -- -- -- -- -- -- -- --
 //! ```
 
-use i2c::{BulkTransfer, Message};
+use i2c_tiny_usb::ScanResult;
 
 type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
 
@@ -23,18 +23,11 @@ pub fn main() -> Result<()> {
 
     println!("     0  1  2  3  4  5  6  7  8  9  a  b  c  d  e  f");
     print!("00:          ");
-    for address in 0x03..=0x77 {
-        // attempt a single zero-length read
-        let result = bus.i2c_transfer(&mut [Message::Read {
-            address,
-            data: &mut [],
-            flags: Default::default(),
-        }]);
-
+    for (address, result) in bus.scan_detailed(0x03..=0x77) {
         match result {
-            Ok(()) => print!("{:02x}", address),
-            Err(i2c_tiny_usb::Error::Nack) => print!("--"),
-            Err(_) => print!("EE"),
+            ScanResult::Present => print!("{:02x}", address),
+            ScanResult::Absent => print!("--"),
+            ScanResult::Error(_) => print!("EE"),
         }
         if address & 0x0f == 0x0f {
             println!();
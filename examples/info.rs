@@ -0,0 +1,53 @@
+//! Prints information about the connected adapter.
+//!
+//! ```
+//! $ cargo run --example info
+//! vendor/product: 0403:c631
+//! name: Till Harbaum i2c-tiny-usb
+//! bus 001 device 005, serial: none
+//! configuration 1
+//! interface 0.0: class ff/00/00, 0 endpoints
+//! functionality: 0x0eff0009
+//! read flags: (empty)
+//! write flags: (empty)
+//! bus delay: default
+//! last status: 0
+//! ```
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+pub fn main() -> Result<()> {
+    let bus = i2c_tiny_usb::I2c::open_single_device()?;
+
+    let info = bus.device_info()?;
+    println!(
+        "vendor/product: {:04x}:{:04x}",
+        info.vendor_id, info.product_id
+    );
+    println!(
+        "name: {} {}",
+        bus.manufacturer_string().as_deref().unwrap_or("unknown"),
+        bus.product_string().as_deref().unwrap_or("unknown")
+    );
+    println!(
+        "bus {:03} device {:03}, serial: {}",
+        info.bus_number,
+        info.address,
+        info.serial.as_deref().unwrap_or("none")
+    );
+    println!("configuration {}", bus.active_config()?);
+    for interface in bus.interfaces()? {
+        println!(
+            "interface {}.{}: class {:02x}/{:02x}/{:02x}, {} endpoints",
+            interface.number,
+            interface.alt_setting,
+            interface.class_code,
+            interface.sub_class_code,
+            interface.protocol_code,
+            interface.endpoints.len()
+        );
+    }
+    println!("{}", bus.status_report());
+
+    Ok(())
+}
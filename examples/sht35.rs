@@ -7,7 +7,8 @@
 //! ```
 
 use i2c::Address;
-use std::io::{Read, Write};
+use i2c_tiny_usb::checksum::read_sensirion_words;
+use std::io::Write;
 
 type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
 
@@ -31,14 +32,13 @@ pub fn main() -> Result<()> {
     bus.write_all(&CMD_MEASURE_SINGLE.to_be_bytes())?;
     sleep(Duration::from_millis(100)); // up to 15ms per datasheet
 
-    // read the measurement results
-    let mut buf = [0u8; 6];
+    // read the measurement results, each followed by a CRC
+    let mut words = [0u16; 2];
     bus.write_all(&CMD_FETCH_DATA.to_be_bytes())?;
-    bus.read_exact(&mut buf)?;
+    read_sensirion_words(&mut bus, &mut words)?;
 
-    let temp = u16::from_be_bytes([buf[0], buf[1]]) as f32 * (175.0 / 65565.0) - 45.0;
-    let humidity = u16::from_be_bytes([buf[3], buf[4]]) as f32 * (100.0 / 65565.0);
-    // the buffer also contains CRCs at buf[2] and buf[5] which are not verified here
+    let temp = words[0] as f32 * (175.0 / 65565.0) - 45.0;
+    let humidity = words[1] as f32 * (100.0 / 65565.0);
 
     println!(" T = {:.2}°C", temp);
     println!("RH = {:.2}%", humidity);
@@ -0,0 +1,90 @@
+//! Asynchronous wrapper around [`I2c`], enabled with the `async` feature.
+
+use i2c::Message;
+use rusb::UsbContext;
+use std::sync::{Arc, Mutex};
+
+use crate::{Error, I2c, OwnedMessage, Result};
+
+/// Wraps an [`I2c`] so that transfers can be awaited without blocking other tasks. The USB
+/// communication itself is still blocking but runs on the tokio blocking thread pool.
+///
+/// Cloning an `AsyncI2c` is cheap and all clones share the same device; concurrent transfers are
+/// performed one after another.
+pub struct AsyncI2c<T: UsbContext> {
+    inner: Arc<Mutex<I2c<T>>>,
+}
+
+impl<T: UsbContext> Clone for AsyncI2c<T> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<T: UsbContext + 'static> AsyncI2c<T> {
+    pub fn new(i2c: I2c<T>) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(i2c)),
+        }
+    }
+
+    /// Performs the same transfer as [`i2c::BulkTransfer::i2c_transfer`]. The message data is
+    /// copied to and from the blocking thread.
+    pub async fn transfer(&self, messages: &mut [Message<'_>]) -> Result<()> {
+        // read buffers are copied as well but that's harmless
+        let mut owned: Vec<OwnedMessage> = messages.iter().map(OwnedMessage::from).collect();
+
+        let inner = self.inner.clone();
+        let (result, owned) = tokio::task::spawn_blocking(move || {
+            let mut i2c = inner.lock().unwrap_or_else(|e| e.into_inner());
+            let result = i2c.transfer_owned(&mut owned);
+            (result, owned)
+        })
+        .await
+        .map_err(|_| Error::Usb(rusb::Error::Other))?;
+
+        // copy the data read back to the caller's buffers
+        for (message, owned) in messages.iter_mut().zip(owned) {
+            if let (Message::Read { data, .. }, OwnedMessage::Read { data: read, .. }) =
+                (message, owned)
+            {
+                data.copy_from_slice(&read);
+            }
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::mock::MockConnection;
+    use crate::messages;
+    use crate::protocol::constants::*;
+
+    #[test]
+    fn test_transfer() {
+        let dev = MockConnection::new();
+        dev.schedule_read(CMD_GET_STATUS, I2C_M_RD, 0, &[STATUS_ADDRESS_ACK]);
+        dev.schedule_read(CMD_I2C_IO | CMD_I2C_END, I2C_M_RD, 0x50, &[0xaa, 0xbb]);
+        dev.schedule_read(CMD_GET_STATUS, I2C_M_RD, 0, &[STATUS_IDLE]);
+        dev.schedule_read(CMD_GET_STATUS, I2C_M_RD, 0, &[STATUS_ADDRESS_NAK]);
+        let bus = AsyncI2c::new(I2c::from_connection(dev, Default::default()));
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+
+        let mut buf = [0u8; 2];
+        let mut msgs = [
+            messages::write(0x50, &[0x00]),
+            messages::read(0x50, &mut buf),
+        ];
+        runtime.block_on(bus.transfer(&mut msgs)).unwrap();
+        assert_eq!(buf, [0xaa, 0xbb]);
+
+        let mut msgs = [messages::write(0x51, &[0x00])];
+        assert_eq!(runtime.block_on(bus.transfer(&mut msgs)), Err(Error::Nack));
+    }
+}
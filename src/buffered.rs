@@ -0,0 +1,64 @@
+use rusb::UsbContext;
+use std::io::{Read, Write};
+
+use crate::{Error, I2c, Result};
+
+/// Wrapper around [`I2c`] that collects written data until [`Write::flush`] is called and then
+/// sends it as a single I2C write. This saves USB round-trips for code performing many small
+/// writes that form a single message.
+///
+/// Pending data is flushed before every read and before changing the slave address so that the
+/// order of operations is preserved. It's also flushed when the wrapper is dropped but errors are
+/// ignored in that case so flush explicitly if you need to handle them.
+pub struct BufferedI2c<'a, T: UsbContext> {
+    i2c: &'a mut I2c<T>,
+    buf: Vec<u8>,
+}
+
+impl<'a, T: UsbContext> BufferedI2c<'a, T> {
+    pub fn new(i2c: &'a mut I2c<T>) -> Self {
+        Self {
+            i2c,
+            buf: Vec::new(),
+        }
+    }
+}
+
+impl<T: UsbContext> Drop for BufferedI2c<'_, T> {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+impl<T: UsbContext> i2c::Master for BufferedI2c<'_, T> {
+    type Error = Error;
+}
+
+impl<T: UsbContext> i2c::Address for BufferedI2c<'_, T> {
+    fn set_slave_address(&mut self, addr: u16, tenbit: bool) -> Result<()> {
+        self.flush()?;
+        self.i2c.set_slave_address(addr, tenbit)
+    }
+}
+
+impl<T: UsbContext> Read for BufferedI2c<'_, T> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.flush()?;
+        self.i2c.read(buf)
+    }
+}
+
+impl<T: UsbContext> Write for BufferedI2c<'_, T> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        if !self.buf.is_empty() {
+            self.i2c.write_all(&self.buf)?;
+            self.buf.clear();
+        }
+        Ok(())
+    }
+}
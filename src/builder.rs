@@ -0,0 +1,81 @@
+use rusb::{Device, GlobalContext, UsbContext};
+use std::time::Duration;
+
+use crate::{i2c_impl::single_device, I2c, Result};
+
+/// Collects the configuration of an [`I2c`] and applies it right after the device is opened.
+/// Settings that are not specified are left at the defaults described in the corresponding
+/// [`I2c`] setters.
+#[derive(Debug, Clone, Default)]
+pub struct I2cBuilder {
+    interface: u8,
+    timeout: Option<Duration>,
+    bus_delay: Option<Duration>,
+    max_transfer_len: Option<usize>,
+    retries: u8,
+    retry_backoff: Duration,
+}
+
+impl I2cBuilder {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// See [`I2c::open_interface`].
+    pub fn interface(mut self, interface: u8) -> Self {
+        self.interface = interface;
+        self
+    }
+
+    /// See [`I2c::set_timeout`].
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// See [`I2c::set_bus_delay`].
+    pub fn bus_delay(mut self, delay: Duration) -> Self {
+        self.bus_delay = Some(delay);
+        self
+    }
+
+    /// See [`I2c::set_max_transfer_len`].
+    pub fn max_transfer_len(mut self, len: usize) -> Self {
+        self.max_transfer_len = Some(len);
+        self
+    }
+
+    /// See [`I2c::set_retries`].
+    pub fn retries(mut self, count: u8) -> Self {
+        self.retries = count;
+        self
+    }
+
+    /// See [`I2c::set_retry_backoff`].
+    pub fn retry_backoff(mut self, backoff: Duration) -> Self {
+        self.retry_backoff = backoff;
+        self
+    }
+
+    /// Opens `device` and applies the configuration.
+    pub fn open_device<T: UsbContext>(&self, device: &Device<T>) -> Result<I2c<T>> {
+        let mut i2c = I2c::open_interface(device, self.interface)?;
+        if let Some(timeout) = self.timeout {
+            i2c.set_timeout(timeout);
+        }
+        if let Some(delay) = self.bus_delay {
+            i2c.set_bus_delay(delay)?;
+        }
+        if let Some(len) = self.max_transfer_len {
+            i2c.set_max_transfer_len(len)?;
+        }
+        i2c.set_retries(self.retries);
+        i2c.set_retry_backoff(self.retry_backoff);
+        Ok(i2c)
+    }
+
+    /// Like [`I2c::open_single_device`] but applies the configuration.
+    pub fn open_single_device(&self) -> Result<I2c<GlobalContext>> {
+        self.open_device(&single_device()?)
+    }
+}
@@ -0,0 +1,78 @@
+//! Checksums commonly used by I2C peripherals to protect the data they return.
+
+use std::io::{Error, ErrorKind, Read};
+
+/// Computes a CRC-8 without reflection or final XOR.
+fn crc8(data: &[u8], polynomial: u8, init: u8) -> u8 {
+    let mut crc = init;
+    for byte in data {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 {
+                (crc << 1) ^ polynomial
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// Computes the CRC-8 used by Sensirion sensors (polynomial 0x31, initial value 0xff, no
+/// reflection or final XOR).
+pub fn crc8_sensirion(data: &[u8]) -> u8 {
+    crc8(data, 0x31, 0xff)
+}
+
+/// Computes the SMBus packet error code (CRC-8 with polynomial 0x07 and initial value 0). It
+/// covers all bytes of the transaction including the address bytes with the R/W bit.
+pub fn crc8_smbus(data: &[u8]) -> u8 {
+    crc8(data, 0x07, 0x00)
+}
+
+/// Reads `words.len()` big-endian 16-bit words where each word is followed by its
+/// [`crc8_sensirion`] checksum, as returned by SHT3x and many other Sensirion sensors. All the
+/// data is read with a single `read_exact` call, i.e. in a single I2C read when used with
+/// [`crate::I2c`]. Returns an error of kind [`ErrorKind::InvalidData`] if any checksum doesn't
+/// match.
+pub fn read_sensirion_words(reader: &mut impl Read, words: &mut [u16]) -> std::io::Result<()> {
+    let mut buf = vec![0u8; words.len() * 3];
+    reader.read_exact(&mut buf)?;
+    for (word, chunk) in words.iter_mut().zip(buf.chunks_exact(3)) {
+        if crc8_sensirion(&chunk[..2]) != chunk[2] {
+            return Err(Error::new(ErrorKind::InvalidData, "CRC mismatch"));
+        }
+        *word = u16::from_be_bytes([chunk[0], chunk[1]]);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crc8_sensirion() {
+        // example from the SHT3x datasheet
+        assert_eq!(crc8_sensirion(&[0xbe, 0xef]), 0x92);
+        assert_eq!(crc8_sensirion(&[]), 0xff);
+    }
+
+    #[test]
+    fn test_crc8_smbus() {
+        assert_eq!(crc8_smbus(b"123456789"), 0xf4);
+        assert_eq!(crc8_smbus(&[]), 0x00);
+    }
+
+    #[test]
+    fn test_read_sensirion_words() {
+        let mut words = [0u16; 2];
+        let data: &[u8] = &[0xbe, 0xef, 0x92, 0x00, 0x00, 0x81];
+        read_sensirion_words(&mut &data[..], &mut words).unwrap();
+        assert_eq!(words, [0xbeef, 0x0000]);
+
+        let corrupt: &[u8] = &[0xbe, 0xef, 0x92, 0x00, 0x01, 0x81];
+        let err = read_sensirion_words(&mut &corrupt[..], &mut words).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+}
@@ -2,10 +2,15 @@ use std::time::Duration;
 
 use rusb::{DeviceHandle, UsbContext};
 
-/// Trait used in `crate::protocol` to communicate with the USB device. Provides only control
-/// transfers since this is what the i2c-tiny-usb protocol uses. Can be replaced with
-/// `MockConnection` for testing.
-pub(crate) trait Connection {
+/// Transport used to communicate with the adapter. Provides only control transfers since this is
+/// what the i2c-tiny-usb protocol uses. Implemented for [`rusb::DeviceHandle`] and can be
+/// implemented for other transports used with [`crate::I2c::from_connection`].
+///
+/// Implementations must follow the contract of [`rusb::DeviceHandle::read_control`]: the number
+/// of bytes returned by `read_control` is at most `buf.len()` and the ones past it are left
+/// untouched. A connection reporting more is treated as a protocol error, the transfer fails
+/// with `rusb::Error::Overflow`.
+pub trait Connection {
     fn read_control(
         &self,
         request_type: u8,
@@ -61,90 +66,199 @@ pub mod mock {
     use std::cell::RefCell;
     use std::collections::VecDeque;
 
+    /// Request types of the vendor requests sent by `crate::protocol`.
+    pub const REQUEST_TYPE_IN: u8 = rusb::constants::LIBUSB_REQUEST_TYPE_VENDOR
+        | rusb::constants::LIBUSB_RECIPIENT_INTERFACE
+        | rusb::constants::LIBUSB_ENDPOINT_IN;
+    pub const REQUEST_TYPE_OUT: u8 = rusb::constants::LIBUSB_REQUEST_TYPE_VENDOR
+        | rusb::constants::LIBUSB_RECIPIENT_INTERFACE
+        | rusb::constants::LIBUSB_ENDPOINT_OUT;
+
     #[derive(Debug, Default, Clone)]
     pub struct Transaction {
+        pub request_type: u8,
         pub request: u8,
         pub value: u16,
         pub index: u16,
         pub data: Vec<u8>,
+        /// Error returned instead of `data` when the transaction is performed.
+        pub error: Option<rusb::Error>,
     }
 
+    /// Writes are matched against the queue filled by `expect_write()` first. Writes performed
+    /// when the queue is empty are recorded in `last_writes` for `pop_write()` instead.
     pub struct MockConnection {
         pub last_writes: RefCell<Vec<Transaction>>,
         pub next_reads: RefCell<VecDeque<Transaction>>,
+        pub next_write_error: RefCell<Option<rusb::Error>>,
+        pub expected_writes: RefCell<VecDeque<Transaction>>,
+        pub mismatched_writes: RefCell<Vec<(Transaction, Transaction)>>,
+        /// Request and timeout of every control transfer performed, in order.
+        pub timeouts: RefCell<Vec<(u8, Duration)>>,
     }
 
     impl Connection for MockConnection {
         fn read_control(
             &self,
-            _request_type: u8,
+            request_type: u8,
             request: u8,
             value: u16,
             index: u16,
             buf: &mut [u8],
-            _timeout: Duration,
+            timeout: Duration,
         ) -> rusb::Result<usize> {
+            self.timeouts.borrow_mut().push((request, timeout));
             let mut next_reads = self.next_reads.borrow_mut();
             let t = match next_reads.pop_front() {
                 None => return Err(rusb::Error::Io),
                 Some(x) => x,
             };
-            if t.request != request
+            if t.request_type != request_type
+                || t.request != request
                 || t.value != value
                 || t.index != index
-                || t.data.len() != buf.len()
             {
                 // the read request doesn't match the scheduled response
                 return Err(rusb::Error::Io);
             }
-            buf.copy_from_slice(&t.data);
-            Ok(buf.len())
+            if let Some(e) = t.error {
+                return Err(e);
+            }
+            if t.data.len() > buf.len() {
+                // libusb reports an overflow when the device sends more data than requested
+                return Err(rusb::Error::Overflow);
+            }
+            // scheduling less data than requested simulates a short transfer
+            buf[..t.data.len()].copy_from_slice(&t.data);
+            Ok(t.data.len())
         }
 
         fn write_control(
             &self,
-            _request_type: u8,
+            request_type: u8,
             request: u8,
             value: u16,
             index: u16,
             buf: &[u8],
-            _timeout: Duration,
+            timeout: Duration,
         ) -> rusb::Result<usize> {
-            let mut writes = self.last_writes.borrow_mut();
-            writes.push(Transaction {
+            self.timeouts.borrow_mut().push((request, timeout));
+            if let Some(e) = self.next_write_error.borrow_mut().take() {
+                return Err(e);
+            }
+            let t = Transaction {
+                request_type,
                 request,
                 value,
                 index,
                 data: buf.into(),
-            });
+                error: None,
+            };
+            match self.expected_writes.borrow_mut().pop_front() {
+                None => self.last_writes.borrow_mut().push(t),
+                Some(expected) => {
+                    if !expected.matches(&t) {
+                        self.mismatched_writes.borrow_mut().push((expected, t));
+                    }
+                }
+            }
             Ok(buf.len())
         }
     }
 
+    impl Transaction {
+        fn matches(&self, other: &Transaction) -> bool {
+            self.request_type == other.request_type
+                && self.request == other.request
+                && self.value == other.value
+                && self.index == other.index
+                && self.data == other.data
+        }
+    }
+
     impl MockConnection {
         pub fn new() -> Self {
             Self {
                 last_writes: RefCell::new(Vec::new()),
                 next_reads: RefCell::new(VecDeque::new()),
+                next_write_error: RefCell::new(None),
+                expected_writes: RefCell::new(VecDeque::new()),
+                mismatched_writes: RefCell::new(Vec::new()),
+                timeouts: RefCell::new(Vec::new()),
             }
         }
 
         pub fn schedule_read(&self, request: u8, value: u16, index: u16, data: &[u8]) {
             self.next_reads.borrow_mut().push_back(Transaction {
+                request_type: REQUEST_TYPE_IN,
                 request,
                 value,
                 index,
                 data: data.into(),
+                error: None,
+            });
+        }
+
+        /// Schedules a read that fails with `error`. The request still has to match.
+        pub fn schedule_read_error(&self, request: u8, value: u16, index: u16, error: rusb::Error) {
+            self.next_reads.borrow_mut().push_back(Transaction {
+                request_type: REQUEST_TYPE_IN,
+                request,
+                value,
+                index,
+                data: Vec::new(),
+                error: Some(error),
             });
         }
 
+        /// Makes the next write fail with `error` without recording it.
+        pub fn fail_next_write(&self, error: rusb::Error) {
+            *self.next_write_error.borrow_mut() = Some(error);
+        }
+
+        /// Adds a write to the queue of writes expected in this order. Checked by `verify()`.
+        pub fn expect_write(&self, request: u8, value: u16, index: u16, data: &[u8]) {
+            self.expected_writes.borrow_mut().push_back(Transaction {
+                request_type: REQUEST_TYPE_OUT,
+                request,
+                value,
+                index,
+                data: data.into(),
+                error: None,
+            });
+        }
+
+        /// Panics if any expected write didn't happen or didn't match, if there were writes
+        /// beyond the expected ones or if any scheduled reads were not performed.
+        pub fn verify(&self) {
+            let expected = self.expected_writes.borrow();
+            assert!(
+                expected.is_empty(),
+                "expected writes not performed: {expected:?}"
+            );
+            let mismatched = self.mismatched_writes.borrow();
+            assert!(
+                mismatched.is_empty(),
+                "mismatched (expected, actual) writes: {mismatched:?}"
+            );
+            let unexpected = self.last_writes.borrow();
+            assert!(unexpected.is_empty(), "unexpected writes: {unexpected:?}");
+            let reads = self.next_reads.borrow();
+            assert!(reads.is_empty(), "scheduled reads not performed: {reads:?}");
+        }
+
         pub fn pop_write(&self, request: u8, value: u16, index: u16, data: &[u8]) -> bool {
             let mut writes = self.last_writes.borrow_mut();
             match writes.pop() {
                 None => false,
-                Some(t) => {
-                    t.request == request && t.value == value && t.index == index && t.data == data
-                }
+                Some(t) => t.matches(&Transaction {
+                    request_type: REQUEST_TYPE_OUT,
+                    request,
+                    value,
+                    index,
+                    data: data.into(),
+                    error: None,
+                }),
             }
         }
 
@@ -152,4 +266,77 @@ pub mod mock {
             !self.last_writes.borrow().is_empty()
         }
     }
+
+    /// Connection claiming to have read one byte more than fits in the buffer.
+    pub struct OverReporting;
+
+    impl Connection for OverReporting {
+        fn read_control(
+            &self,
+            _request_type: u8,
+            _request: u8,
+            _value: u16,
+            _index: u16,
+            buf: &mut [u8],
+            _timeout: Duration,
+        ) -> rusb::Result<usize> {
+            buf.fill(0xaa);
+            Ok(buf.len() + 1)
+        }
+
+        fn write_control(
+            &self,
+            _request_type: u8,
+            _request: u8,
+            _value: u16,
+            _index: u16,
+            buf: &[u8],
+            _timeout: Duration,
+        ) -> rusb::Result<usize> {
+            Ok(buf.len())
+        }
+    }
+
+    #[test]
+    fn test_expect_write() {
+        let dev = MockConnection::new();
+        dev.expect_write(1, 2, 3, &[4]);
+        dev.write_control(REQUEST_TYPE_OUT, 1, 2, 3, &[4], Duration::ZERO)
+            .unwrap();
+        dev.verify();
+    }
+
+    #[test]
+    #[should_panic(expected = "mismatched")]
+    fn test_expect_write_mismatch() {
+        let dev = MockConnection::new();
+        dev.expect_write(1, 2, 3, &[4]);
+        dev.write_control(REQUEST_TYPE_OUT, 1, 2, 3, &[5], Duration::ZERO)
+            .unwrap();
+        dev.verify();
+    }
+
+    #[test]
+    #[should_panic(expected = "unexpected")]
+    fn test_unexpected_write() {
+        let dev = MockConnection::new();
+        dev.write_control(REQUEST_TYPE_OUT, 1, 2, 3, &[4], Duration::ZERO)
+            .unwrap();
+        dev.verify();
+    }
+
+    #[test]
+    fn test_request_type_mismatch() {
+        let dev = MockConnection::new();
+        dev.schedule_read(1, 2, 3, &[4]);
+        let mut buf = [0u8; 1];
+        assert_eq!(
+            dev.read_control(REQUEST_TYPE_OUT, 1, 2, 3, &mut buf, Duration::ZERO),
+            Err(rusb::Error::Io)
+        );
+        dev.expect_write(1, 2, 3, &[4]);
+        dev.write_control(REQUEST_TYPE_IN, 1, 2, 3, &[4], Duration::ZERO)
+            .unwrap();
+        assert_eq!(dev.mismatched_writes.borrow().len(), 1);
+    }
 }
@@ -0,0 +1,213 @@
+//! Simulated adapter for testing code using [`crate::I2c`] without hardware, enabled with the
+//! `dry-run` feature.
+
+use i2c::{ReadFlags, WriteFlags};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use crate::protocol::constants::*;
+use crate::{Connection, OwnedMessage};
+
+/// [`Connection`] simulating an adapter with peripherals that accept every write and answer reads
+/// with data queued by [`DryRun::push_read`]. Pass a clone to [`crate::I2c::from_connection`]
+/// and inspect the messages it received with [`DryRun::messages`].
+///
+/// Reads are answered from the front of the queue if it holds data for the same address and
+/// NACKed otherwise. A queued entry shorter than the read results in a short transfer and one
+/// longer than it is consumed by the following reads, e.g. the continuations of a read split by
+/// [`crate::I2c::set_max_transfer_len`].
+#[derive(Debug, Clone, Default)]
+pub struct DryRun {
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    reads: VecDeque<(u16, Vec<u8>)>,
+    messages: Vec<OwnedMessage>,
+    status: u8,
+}
+
+impl DryRun {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Same as [`DryRun::push_read`] but can be chained after [`DryRun::new`].
+    pub fn with_read(self, address: u16, data: &[u8]) -> Self {
+        self.push_read(address, data);
+        self
+    }
+
+    /// Queues `data` to be returned by the next read from `address`.
+    pub fn push_read(&self, address: u16, data: &[u8]) {
+        self.lock().reads.push_back((address, data.into()));
+    }
+
+    /// Returns the number of queued reads that weren't performed yet.
+    pub fn pending_reads(&self) -> usize {
+        self.lock().reads.len()
+    }
+
+    /// Returns the I2C messages received so far, one per control transfer. NACKed reads are not
+    /// included.
+    pub fn messages(&self) -> Vec<OwnedMessage> {
+        self.lock().messages.clone()
+    }
+
+    /// Returns the address and data of every write received so far.
+    pub fn writes(&self) -> Vec<(u16, Vec<u8>)> {
+        self.lock()
+            .messages
+            .iter()
+            .filter_map(|message| match message {
+                OwnedMessage::Write { address, data, .. } => Some((*address, data.clone())),
+                OwnedMessage::Read { .. } => None,
+            })
+            .collect()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+fn is_i2c_io(request: u8) -> bool {
+    request & !(CMD_I2C_BEGIN | CMD_I2C_END) == CMD_I2C_IO
+}
+
+/// Copies as much of `data` as fits into `buf`.
+fn reply(buf: &mut [u8], data: &[u8]) -> usize {
+    let len = data.len().min(buf.len());
+    buf[..len].copy_from_slice(&data[..len]);
+    len
+}
+
+impl Connection for DryRun {
+    fn read_control(
+        &self,
+        _request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        buf: &mut [u8],
+        _timeout: Duration,
+    ) -> rusb::Result<usize> {
+        let mut state = self.lock();
+        match request {
+            CMD_ECHO => Ok(reply(buf, &value.to_le_bytes())),
+            CMD_GET_FUNC => Ok(reply(
+                buf,
+                &(I2C_FUNC_I2C | I2C_FUNC_PROTOCOL_MANGLING).to_le_bytes(),
+            )),
+            CMD_GET_STATUS => Ok(reply(buf, &[state.status])),
+            request if is_i2c_io(request) => {
+                let address = index;
+                let data = match state.reads.front_mut() {
+                    Some((queued_address, queued)) if *queued_address == address => {
+                        let len = queued.len().min(buf.len());
+                        queued.drain(..len).collect::<Vec<u8>>()
+                    }
+                    _ => {
+                        state.status = STATUS_ADDRESS_NAK;
+                        return Err(rusb::Error::Pipe);
+                    }
+                };
+                if state
+                    .reads
+                    .front()
+                    .is_some_and(|(_, queued)| queued.is_empty())
+                {
+                    state.reads.pop_front();
+                }
+                let mut flags = ReadFlags::empty();
+                if value & I2C_M_NO_RD_ACK != 0 {
+                    flags |= ReadFlags::NACK;
+                }
+                if value & I2C_M_REV_DIR_ADDR != 0 {
+                    flags |= ReadFlags::REVERSE_RW;
+                }
+                if value & I2C_M_NOSTART != 0 {
+                    flags |= ReadFlags::NO_START;
+                }
+                state.status = STATUS_ADDRESS_ACK;
+                state.messages.push(OwnedMessage::Read {
+                    address,
+                    data: data.clone(),
+                    flags,
+                });
+                Ok(reply(buf, &data))
+            }
+            _ => Err(rusb::Error::NotSupported),
+        }
+    }
+
+    fn write_control(
+        &self,
+        _request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        buf: &[u8],
+        _timeout: Duration,
+    ) -> rusb::Result<usize> {
+        let mut state = self.lock();
+        match request {
+            CMD_SET_DELAY => Ok(0),
+            request if is_i2c_io(request) => {
+                let mut flags = WriteFlags::empty();
+                if value & I2C_M_IGNORE_NAK != 0 {
+                    flags |= WriteFlags::IGNORE_NACK;
+                }
+                if value & I2C_M_REV_DIR_ADDR != 0 {
+                    flags |= WriteFlags::REVERSE_RW;
+                }
+                if value & I2C_M_NOSTART != 0 {
+                    flags |= WriteFlags::NO_START;
+                }
+                state.status = STATUS_ADDRESS_ACK;
+                state.messages.push(OwnedMessage::Write {
+                    address: index,
+                    data: buf.into(),
+                    flags,
+                });
+                Ok(buf.len())
+            }
+            _ => Err(rusb::Error::NotSupported),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Error, I2c};
+
+    #[test]
+    fn test_dry_run() {
+        let dry_run = DryRun::new().with_read(0x50, &[0x01, 0x02, 0x03]);
+        let mut bus = I2c::from_connection(dry_run.clone(), Default::default());
+        bus.set_max_transfer_len(2).unwrap();
+
+        let mut buf = [0u8; 3];
+        bus.write_read(0x50, &[0x10], &mut buf).unwrap();
+        assert_eq!(buf, [0x01, 0x02, 0x03]);
+        assert_eq!(dry_run.pending_reads(), 0);
+        assert_eq!(bus.write_read(0x50, &[0x10], &mut buf), Err(Error::Nack));
+
+        assert_eq!(
+            dry_run.writes(),
+            vec![(0x50, vec![0x10]), (0x50, vec![0x10])]
+        );
+        assert_eq!(dry_run.messages().len(), 4);
+        assert_eq!(
+            dry_run.messages()[2],
+            OwnedMessage::Read {
+                address: 0x50,
+                data: vec![0x03],
+                flags: ReadFlags::empty(),
+            }
+        );
+    }
+}
@@ -0,0 +1,396 @@
+use i2c::BulkTransfer;
+use rusb::UsbContext;
+use std::io::Read;
+use std::ops::Range;
+use std::time::Duration;
+
+use crate::i2c_impl::read_full;
+use crate::{messages, Error, I2c, Result, WriteStreamError, WriteStreamFailure};
+
+/// How memory offsets are sent to an [`Eeprom`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EepromAddressing {
+    /// A single address byte. Chips larger than 256 bytes (24C04 to 24C16) take the upper bits of
+    /// the offset from the lowest bits of the I2C address.
+    OneByte,
+    /// Two address bytes, most significant first (24C32 and larger).
+    TwoBytes,
+}
+
+/// Size of the largest chip with one-byte addressing (24C16).
+const MAX_ONE_BYTE_SIZE: usize = 2048;
+/// Size of the largest chip with two-byte addressing (24C512).
+const MAX_TWO_BYTES_SIZE: usize = 65536;
+/// Number of bytes compared by the detection heuristics of [`Eeprom::detect`].
+const PROBE_LEN: usize = 32;
+
+/// Helper for reading and writing I2C EEPROMs of the 24Cxx family.
+pub struct Eeprom<'a, T: UsbContext> {
+    i2c: &'a mut I2c<T>,
+    address: u16,
+    size: usize,
+    addressing: EepromAddressing,
+    page_size: usize,
+    write_cycle_time: Duration,
+}
+
+fn is_uniform(data: &[u8]) -> bool {
+    data.iter().all(|&byte| byte == data[0])
+}
+
+/// Splits `range` into ranges that don't cross multiples of `boundary`.
+fn split_at_boundaries(range: Range<usize>, boundary: usize) -> impl Iterator<Item = Range<usize>> {
+    let mut start = range.start;
+    std::iter::from_fn(move || {
+        if start >= range.end {
+            return None;
+        }
+        let end = ((start / boundary + 1) * boundary).min(range.end);
+        let chunk = start..end;
+        start = end;
+        Some(chunk)
+    })
+}
+
+impl<'a, T: UsbContext> Eeprom<'a, T> {
+    /// Creates a helper for an EEPROM of `size` bytes at I2C address `address`. The page size
+    /// defaults to 8 bytes which is the smallest one in common use and the write cycle time to
+    /// 5ms.
+    pub fn new(
+        i2c: &'a mut I2c<T>,
+        address: u16,
+        size: usize,
+        addressing: EepromAddressing,
+    ) -> Self {
+        Self {
+            i2c,
+            address,
+            size,
+            addressing,
+            page_size: 8,
+            write_cycle_time: Duration::from_millis(5),
+        }
+    }
+
+    /// Creates a helper for the EEPROM at I2C address `address` with the addressing mode and size
+    /// detected by probing the chip. Only reads are performed but the heuristics compare data at
+    /// different offsets, so detection fails with `rusb::Error::Other` if the beginning of the
+    /// chip is too uniform to tell the addressing mode, e.g. when it's erased. Use
+    /// [`Eeprom::new`] if the chip is known.
+    ///
+    /// The size is found by looking for the offset where reads wrap around to the start of the
+    /// chip or, for the one-byte addressing chips occupying several I2C addresses, the first
+    /// address that isn't acknowledged. Other peripherals at the following addresses and
+    /// repetitive content can make a chip look bigger than it is.
+    pub fn detect(i2c: &'a mut I2c<T>, address: u16) -> Result<Self> {
+        let mut eeprom = Self::new(i2c, address, MAX_ONE_BYTE_SIZE, EepromAddressing::OneByte);
+        eeprom.addressing = eeprom.detect_addressing()?;
+        eeprom.size = eeprom.detect_size()?;
+        Ok(eeprom)
+    }
+
+    fn detect_addressing(&mut self) -> Result<EepromAddressing> {
+        // Only single address bytes are sent since a chip with one-byte addressing would take the
+        // second byte of a two-byte address as data to write. A chip with two-byte addressing
+        // takes the single byte as the upper half of the offset, so a read from the middle of the
+        // probed range doesn't return the same data as reading the whole range.
+        let mut start = [0u8; PROBE_LEN];
+        self.i2c.write_read(self.address, &[0], &mut start)?;
+        let mut middle = [0u8; PROBE_LEN / 2];
+        self.i2c
+            .write_read(self.address, &[(PROBE_LEN / 2) as u8], &mut middle)?;
+        if is_uniform(&start[PROBE_LEN / 2..]) {
+            return Err(rusb::Error::Other.into());
+        }
+        if middle == start[PROBE_LEN / 2..] {
+            Ok(EepromAddressing::OneByte)
+        } else {
+            Ok(EepromAddressing::TwoBytes)
+        }
+    }
+
+    fn detect_size(&mut self) -> Result<usize> {
+        let (mut size, max_size) = match self.addressing {
+            EepromAddressing::OneByte => (128, MAX_ONE_BYTE_SIZE),
+            EepromAddressing::TwoBytes => (4096, MAX_TWO_BYTES_SIZE),
+        };
+        self.size = max_size;
+        let mut start = [0u8; PROBE_LEN];
+        self.read(0, &mut start)?;
+        while size < max_size {
+            let mut probe = [0u8; PROBE_LEN];
+            match self.read(size, &mut probe) {
+                Err(Error::Nack) => break,
+                Err(e) => return Err(e),
+                Ok(()) if probe == start && !is_uniform(&start) => break, // wrapped around
+                Ok(()) => size *= 2,
+            }
+        }
+        Ok(size)
+    }
+
+    /// Returns the size of the chip in bytes.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Returns the addressing mode of the chip.
+    pub fn addressing(&self) -> EepromAddressing {
+        self.addressing
+    }
+
+    /// Sets the size of a write page. Writes are split so that none of them crosses a page
+    /// boundary since the chips wrap around to the start of the page instead. Must be a power of
+    /// two.
+    pub fn set_page_size(&mut self, page_size: usize) -> Result<()> {
+        if !page_size.is_power_of_two() {
+            return Err(rusb::Error::InvalidParam.into());
+        }
+        self.page_size = page_size;
+        Ok(())
+    }
+
+    /// Sets how long to wait after writing each page for the chip to finish programming it.
+    pub fn set_write_cycle_time(&mut self, time: Duration) {
+        self.write_cycle_time = time;
+    }
+
+    /// Checks that `len` bytes starting at `offset` fit in the chip.
+    fn check_range(&self, offset: usize, len: usize) -> Result<Range<usize>> {
+        match offset.checked_add(len) {
+            Some(end) if end <= self.size => Ok(offset..end),
+            _ => Err(rusb::Error::InvalidParam.into()),
+        }
+    }
+
+    /// Returns the I2C address and the address bytes selecting `offset`.
+    fn locate(&self, offset: usize) -> (u16, Vec<u8>) {
+        match self.addressing {
+            EepromAddressing::OneByte => (self.address | (offset >> 8) as u16, vec![offset as u8]),
+            EepromAddressing::TwoBytes => (self.address, (offset as u16).to_be_bytes().to_vec()),
+        }
+    }
+
+    /// Returns the largest range that can be accessed without changing the I2C address.
+    fn block_size(&self) -> usize {
+        match self.addressing {
+            EepromAddressing::OneByte => 0x100,
+            EepromAddressing::TwoBytes => 0x10000,
+        }
+    }
+
+    /// Reads `buf.len()` bytes starting at `offset`.
+    pub fn read(&mut self, offset: usize, buf: &mut [u8]) -> Result<()> {
+        let range = self.check_range(offset, buf.len())?;
+        for chunk in split_at_boundaries(range, self.block_size()) {
+            let (address, addr_bytes) = self.locate(chunk.start);
+            let data = &mut buf[chunk.start - offset..chunk.end - offset];
+            self.i2c.write_read(address, &addr_bytes, data)?;
+        }
+        Ok(())
+    }
+
+    /// Reads the whole chip.
+    pub fn dump(&mut self) -> Result<Vec<u8>> {
+        let mut data = vec![0u8; self.size];
+        self.read(0, &mut data)?;
+        Ok(data)
+    }
+
+    /// Writes `data` starting at `offset`, one page at a time. Waits for the write cycle to finish
+    /// after each page so the chip is ready for further commands when this returns.
+    pub fn write(&mut self, offset: usize, data: &[u8]) -> Result<()> {
+        self.write_pages(offset, data, false)
+    }
+
+    /// Like [`Eeprom::write`] but reads each page back after writing it. Returns
+    /// [`Error::VerifyFailed`] with the offset of the first byte that doesn't match, e.g. because
+    /// of a worn cell or write protection. The following pages are not written in that case.
+    pub fn write_verify(&mut self, offset: usize, data: &[u8]) -> Result<()> {
+        self.write_pages(offset, data, true)
+    }
+
+    /// Reads `src` until the end and writes the data starting at `offset`, one page at a time so
+    /// that only a page is buffered. Returns the number of bytes written. Fails with
+    /// `rusb::Error::InvalidParam` once the data doesn't fit in the chip, the pages before that
+    /// are written.
+    pub fn write_stream(
+        &mut self,
+        offset: usize,
+        mut src: impl Read,
+    ) -> std::result::Result<u64, WriteStreamError> {
+        let mut written = 0u64;
+        let mut pos = offset;
+        let mut buf = vec![0u8; self.page_size];
+        loop {
+            // the first page may start in the middle
+            let page_len = self.page_size - pos % self.page_size;
+            let len = read_full(&mut src, &mut buf[..page_len]).map_err(|e| WriteStreamError {
+                written,
+                source: WriteStreamFailure::Read(e),
+            })?;
+            if len == 0 {
+                return Ok(written);
+            }
+            self.write_pages(pos, &buf[..len], false)
+                .map_err(|e| WriteStreamError {
+                    written,
+                    source: e.into(),
+                })?;
+            pos += len;
+            written += len as u64;
+        }
+    }
+
+    fn write_pages(&mut self, offset: usize, data: &[u8], verify: bool) -> Result<()> {
+        let range = self.check_range(offset, data.len())?;
+        for chunk in split_at_boundaries(range, self.page_size) {
+            let page_data = &data[chunk.start - offset..chunk.end - offset];
+            let (address, mut buf) = self.locate(chunk.start);
+            buf.extend_from_slice(page_data);
+            self.i2c
+                .i2c_transfer(&mut [messages::write(address, &buf)])?;
+            std::thread::sleep(self.write_cycle_time);
+            if verify {
+                let mut read_back = vec![0u8; page_data.len()];
+                self.read(chunk.start, &mut read_back)?;
+                if let Some(i) = page_data.iter().zip(&read_back).position(|(a, b)| a != b) {
+                    return Err(Error::VerifyFailed {
+                        offset: chunk.start + i,
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::mock::MockConnection;
+    use crate::protocol::constants::*;
+    use crate::{TraceDirection, TraceEvent};
+    use std::sync::{Arc, Mutex};
+
+    /// Schedules the responses to a [`I2c::write_read`] returning `data`.
+    fn schedule_write_read(dev: &MockConnection, address: u16, data: &[u8]) {
+        dev.schedule_read(CMD_GET_STATUS, I2C_M_RD, 0, &[STATUS_ADDRESS_ACK]);
+        let chunks: Vec<&[u8]> = data.chunks(64).collect();
+        for (i, chunk) in chunks.iter().enumerate() {
+            let end = if i == chunks.len() - 1 {
+                CMD_I2C_END
+            } else {
+                0
+            };
+            dev.schedule_read(CMD_I2C_IO | end, I2C_M_RD, address, chunk);
+        }
+        dev.schedule_read(CMD_GET_STATUS, I2C_M_RD, 0, &[STATUS_IDLE]);
+    }
+
+    #[test]
+    fn test_detect_one_byte() {
+        let content: Vec<u8> = (0..128).collect();
+        let dev = MockConnection::new();
+        schedule_write_read(&dev, 0x50, &content[..32]);
+        schedule_write_read(&dev, 0x50, &content[16..32]);
+        schedule_write_read(&dev, 0x50, &content[..32]);
+        schedule_write_read(&dev, 0x50, &content[..32]); // wrapped around at 128
+        schedule_write_read(&dev, 0x50, &content);
+        let mut bus = I2c::from_connection(dev, Default::default());
+        let mut eeprom = Eeprom::detect(&mut bus, 0x50).unwrap();
+        assert_eq!(eeprom.addressing(), EepromAddressing::OneByte);
+        assert_eq!(eeprom.size(), 128);
+        assert_eq!(eeprom.dump().unwrap(), content);
+    }
+
+    #[test]
+    fn test_detect_two_bytes() {
+        let content: Vec<u8> = (0..32).collect();
+        let dev = MockConnection::new();
+        schedule_write_read(&dev, 0x50, &content);
+        schedule_write_read(&dev, 0x50, &[0xff; 16]); // from a different offset
+        schedule_write_read(&dev, 0x50, &content);
+        schedule_write_read(&dev, 0x50, &content); // wrapped around at 4096
+        let mut bus = I2c::from_connection(dev, Default::default());
+        let eeprom = Eeprom::detect(&mut bus, 0x50).unwrap();
+        assert_eq!(eeprom.addressing(), EepromAddressing::TwoBytes);
+        assert_eq!(eeprom.size(), 4096);
+    }
+
+    #[test]
+    fn test_write_verify() {
+        let dev = MockConnection::new();
+        dev.schedule_read(CMD_GET_STATUS, I2C_M_RD, 0, &[STATUS_ADDRESS_ACK]);
+        schedule_write_read(&dev, 0x50, &[0x01, 0x02]);
+        dev.schedule_read(CMD_GET_STATUS, I2C_M_RD, 0, &[STATUS_ADDRESS_ACK]);
+        schedule_write_read(&dev, 0x50, &[0x03, 0xff]);
+        let mut bus = I2c::from_connection(dev, Default::default());
+        let mut eeprom = Eeprom::new(&mut bus, 0x50, 256, EepromAddressing::OneByte);
+        eeprom.set_page_size(2).unwrap();
+        eeprom.set_write_cycle_time(Duration::ZERO);
+        assert_eq!(
+            eeprom.write_verify(0x10, &[0x01, 0x02, 0x03, 0x04, 0x05]),
+            Err(Error::VerifyFailed { offset: 0x13 })
+        );
+    }
+
+    #[test]
+    fn test_write_stream() {
+        let dev = MockConnection::new();
+        for _ in 0..4 {
+            dev.schedule_read(CMD_GET_STATUS, I2C_M_RD, 0, &[STATUS_ADDRESS_ACK]);
+        }
+        let mut bus = I2c::from_connection(dev, Default::default());
+        let writes = Arc::new(Mutex::new(Vec::new()));
+        bus.set_trace_hook(Some({
+            let writes = writes.clone();
+            Box::new(move |e: &TraceEvent| {
+                if e.direction == TraceDirection::Out {
+                    writes.lock().unwrap().push((e.index, e.data.to_vec()));
+                }
+            })
+        }));
+        let mut eeprom = Eeprom::new(&mut bus, 0x50, 256, EepromAddressing::OneByte);
+        eeprom.set_page_size(2).unwrap();
+        eeprom.set_write_cycle_time(Duration::ZERO);
+        assert_eq!(eeprom.write_stream(0x11, &[1u8, 2, 3, 4][..]).unwrap(), 4);
+        assert_eq!(
+            *writes.lock().unwrap(),
+            [
+                (0x50, vec![0x11, 1]),
+                (0x50, vec![0x12, 2, 3]),
+                (0x50, vec![0x14, 4])
+            ]
+        );
+
+        // the page that fits is written before running out of space
+        let err = eeprom.write_stream(0xff, &[5u8, 6][..]).unwrap_err();
+        assert_eq!(err.written, 1);
+        assert!(matches!(
+            err.source,
+            WriteStreamFailure::Write(Error::Usb(rusb::Error::InvalidParam))
+        ));
+    }
+
+    #[test]
+    fn test_detect_uniform() {
+        let dev = MockConnection::new();
+        schedule_write_read(&dev, 0x50, &[0xff; 32]);
+        schedule_write_read(&dev, 0x50, &[0xff; 16]);
+        let mut bus = I2c::from_connection(dev, Default::default());
+        assert_eq!(
+            Eeprom::detect(&mut bus, 0x50).err(),
+            Some(Error::Usb(rusb::Error::Other))
+        );
+    }
+
+    #[test]
+    fn test_split_at_boundaries() {
+        let chunks: Vec<_> = split_at_boundaries(5..20, 8).collect();
+        assert_eq!(chunks, [5..8, 8..16, 16..20]);
+        let chunks: Vec<_> = split_at_boundaries(8..17, 8).collect();
+        assert_eq!(chunks, [8..16, 16..17]);
+        assert_eq!(split_at_boundaries(3..3, 8).count(), 0);
+    }
+}
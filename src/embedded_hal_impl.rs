@@ -0,0 +1,106 @@
+//! Implementation of the `embedded-hal` 1.0 I2C traits, enabled with the `embedded-hal` feature.
+
+use embedded_hal::i2c::{ErrorKind, ErrorType, NoAcknowledgeSource, Operation, SevenBitAddress};
+use i2c::Message;
+use rusb::UsbContext;
+
+use crate::{Error, I2c, Result};
+
+impl embedded_hal::i2c::Error for Error {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            Error::Nack => ErrorKind::NoAcknowledge(NoAcknowledgeSource::Address),
+            Error::DataNack => ErrorKind::NoAcknowledge(NoAcknowledgeSource::Data),
+            Error::BusError { .. } => ErrorKind::Bus,
+            Error::Usb(_)
+            | Error::UnexpectedStatus(_)
+            | Error::InvalidFunctionality(_)
+            | Error::ShortTransfer { .. }
+            | Error::MultipleDevices { .. }
+            | Error::EchoMismatch { .. }
+            | Error::Crc
+            | Error::VerifyFailed { .. }
+            | Error::NotI2cTinyUsb { .. } => ErrorKind::Other,
+        }
+    }
+}
+
+impl<T: UsbContext> ErrorType for I2c<T> {
+    type Error = Error;
+}
+
+// The trait requires adjacent operations of the same type to be sent without a repeated start
+// between them, e.g. a register pointer and the data written to it. They are merged into a single
+// message for that.
+impl<T: UsbContext> embedded_hal::i2c::I2c<SevenBitAddress> for I2c<T> {
+    fn transaction(&mut self, address: u8, operations: &mut [Operation<'_>]) -> Result<()> {
+        let mut messages: Vec<Message> = operations
+            .iter_mut()
+            .map(|op| match op {
+                Operation::Read(data) => Message::Read {
+                    address: address.into(),
+                    data,
+                    flags: Default::default(),
+                },
+                Operation::Write(data) => Message::Write {
+                    address: address.into(),
+                    data,
+                    flags: Default::default(),
+                },
+            })
+            .collect();
+        self.transfer_merging(&mut messages)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::mock::MockConnection;
+    use crate::protocol::constants::*;
+    use crate::{TraceDirection, TraceEvent};
+    use embedded_hal::i2c::I2c as _;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_transaction_merges_adjacent() {
+        let dev = MockConnection::new();
+        dev.schedule_read(CMD_GET_STATUS, I2C_M_RD, 0, &[STATUS_ADDRESS_ACK]);
+        dev.schedule_read(
+            CMD_I2C_IO | CMD_I2C_END,
+            I2C_M_RD,
+            0x50,
+            &[0x01, 0x02, 0x03],
+        );
+        dev.schedule_read(CMD_GET_STATUS, I2C_M_RD, 0, &[STATUS_IDLE]);
+        let mut bus = I2c::from_connection(dev, Default::default());
+        let writes = Arc::new(Mutex::new(Vec::new()));
+        bus.set_trace_hook(Some({
+            let writes = writes.clone();
+            Box::new(move |e: &TraceEvent| {
+                if e.direction == TraceDirection::Out {
+                    writes.lock().unwrap().push((e.request, e.data.to_vec()));
+                }
+            })
+        }));
+
+        let mut first = [0u8; 1];
+        let mut second = [0u8; 2];
+        bus.transaction(
+            0x50,
+            &mut [
+                Operation::Write(&[0x10]),
+                Operation::Write(&[0xaa, 0xbb]),
+                Operation::Read(&mut first),
+                Operation::Read(&mut second),
+            ],
+        )
+        .unwrap();
+        assert_eq!(
+            *writes.lock().unwrap(),
+            [(CMD_I2C_IO | CMD_I2C_BEGIN, vec![0x10, 0xaa, 0xbb])]
+        );
+        assert_eq!(first, [0x01]);
+        assert_eq!(second, [0x02, 0x03]);
+    }
+}
@@ -3,34 +3,141 @@ pub enum Error {
     #[error("USB error")]
     Usb(#[from] rusb::Error),
 
+    /// The peripheral didn't acknowledge its address, typically because it's not present.
     #[error("no acknowledgement from the i2c device")]
     Nack,
+
+    /// The peripheral acknowledged its address but stopped accepting data in the middle of a write.
+    /// The firmware doesn't report how many bytes were acknowledged.
+    #[error("i2c device stopped acknowledging data")]
+    DataNack,
+
+    /// The device reported a status value not defined by the i2c-tiny-usb protocol when opened,
+    /// typically a clone in a broken state.
+    #[error("unexpected status {0} reported by the device")]
+    UnexpectedStatus(u8),
+
+    /// The device reported a status value not defined by the i2c-tiny-usb protocol after a
+    /// transfer. The reference firmware never does but clones with extended firmware may use such
+    /// values for bus errors like arbitration loss.
+    #[error("bus error, status {status} reported by the device")]
+    BusError { status: u8 },
+
+    /// A USB control transfer moved fewer bytes than requested. Receiving more bytes than
+    /// requested is reported as [`rusb::Error::Overflow`] instead.
+    #[error("only {transferred} of {requested} bytes were transferred")]
+    ShortTransfer {
+        requested: usize,
+        transferred: usize,
+    },
+
+    /// A single adapter was requested but more of them are connected. Use
+    /// [`crate::I2c::open_by_serial`] or similar to pick one.
+    #[error("{count} i2c-tiny-usb devices found, expected a single one")]
+    MultipleDevices { count: usize },
+
+    /// The device echoed back a different value than it was sent, see
+    /// [`crate::I2c::echo_stress`].
+    #[error("device echoed {received:#06x} instead of {sent:#06x}")]
+    EchoMismatch { sent: u16, received: u16 },
+
+    /// Data read back after a write doesn't match what was written, see
+    /// [`crate::Eeprom::write_verify`].
+    #[error("verification failed at offset {offset:#x}")]
+    VerifyFailed { offset: usize },
+
+    /// The device reported a functionality bitmask with bits that no known firmware sets, most
+    /// likely garbage from a broken or counterfeit adapter.
+    #[error("invalid functionality {0:#010x} reported by the device")]
+    InvalidFunctionality(u32),
+
+    /// The checksum of the data returned by the peripheral doesn't match.
+    #[error("checksum mismatch")]
+    Crc,
+
+    /// The USB device has the IDs of a supported adapter but doesn't respond to the i2c-tiny-usb
+    /// commands as expected. `source` is the error of the check that failed.
+    #[error("the USB device is not an i2c-tiny-usb adapter")]
+    NotI2cTinyUsb { source: Box<Error> },
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
 
+/// Error of a multi-message transfer that also identifies the message that failed.
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+#[error("transfer failed at message {failed_index}")]
+pub struct TransferError {
+    /// Index of the message that failed. The messages before it were completed.
+    pub failed_index: usize,
+    pub source: Error,
+}
+
+impl From<TransferError> for Error {
+    fn from(value: TransferError) -> Self {
+        value.source
+    }
+}
+
+/// Error of a write streamed from a [`std::io::Read`] source that also reports how much was
+/// written before it.
+#[derive(thiserror::Error, Debug)]
+#[error("streamed write failed after {written} bytes")]
+pub struct WriteStreamError {
+    /// Number of bytes written and acknowledged before the error.
+    pub written: u64,
+    pub source: WriteStreamFailure,
+}
+
+/// What failed in a streamed write, see [`WriteStreamError`].
+#[derive(thiserror::Error, Debug)]
+pub enum WriteStreamFailure {
+    /// Reading the data from the source failed. The error is passed on unchanged.
+    #[error("reading the source failed")]
+    Read(#[source] std::io::Error),
+
+    /// Writing the data to the peripheral failed.
+    #[error(transparent)]
+    Write(#[from] Error),
+}
+
+// The original error is kept as the payload so that it's shown in messages and converting back
+// with `From<std::io::Error>` returns it unchanged.
 impl From<Error> for std::io::Error {
     fn from(value: Error) -> Self {
         use std::io::ErrorKind;
-        match value {
-            Error::Usb(rusb::Error::InvalidParam) => ErrorKind::InvalidInput.into(),
-            Error::Usb(rusb::Error::Access) => ErrorKind::PermissionDenied.into(),
-            Error::Usb(rusb::Error::NoDevice) => ErrorKind::ConnectionRefused.into(),
-            Error::Usb(rusb::Error::NotFound) => ErrorKind::Unsupported.into(),
-            Error::Usb(rusb::Error::Busy) => ErrorKind::ResourceBusy.into(),
-            Error::Usb(rusb::Error::Timeout) => ErrorKind::TimedOut.into(),
-            Error::Usb(rusb::Error::Pipe) => ErrorKind::BrokenPipe.into(),
-            Error::Usb(rusb::Error::Interrupted) => ErrorKind::Interrupted.into(),
-            Error::Usb(rusb::Error::NoMem) => ErrorKind::OutOfMemory.into(),
-            Error::Usb(rusb::Error::NotSupported) => ErrorKind::InvalidInput.into(),
-            Error::Usb(_) => ErrorKind::Other.into(),
-            Error::Nack => ErrorKind::NotConnected.into(),
-        }
+        let kind = match value {
+            Error::Usb(rusb::Error::InvalidParam) => ErrorKind::InvalidInput,
+            Error::Usb(rusb::Error::Access) => ErrorKind::PermissionDenied,
+            Error::Usb(rusb::Error::NoDevice) => ErrorKind::ConnectionRefused,
+            Error::Usb(rusb::Error::NotFound) => ErrorKind::Unsupported,
+            Error::Usb(rusb::Error::Busy) => ErrorKind::ResourceBusy,
+            Error::Usb(rusb::Error::Timeout) => ErrorKind::TimedOut,
+            Error::Usb(rusb::Error::Pipe) => ErrorKind::BrokenPipe,
+            Error::Usb(rusb::Error::Interrupted) => ErrorKind::Interrupted,
+            Error::Usb(rusb::Error::NoMem) => ErrorKind::OutOfMemory,
+            Error::Usb(rusb::Error::NotSupported) => ErrorKind::InvalidInput,
+            Error::Usb(_) => ErrorKind::Other,
+            Error::Nack => ErrorKind::NotConnected,
+            Error::DataNack => ErrorKind::ConnectionAborted,
+            Error::UnexpectedStatus(_) | Error::InvalidFunctionality(_) => ErrorKind::InvalidData,
+            Error::ShortTransfer { .. } => ErrorKind::UnexpectedEof,
+            Error::BusError { .. }
+            | Error::MultipleDevices { .. }
+            | Error::NotI2cTinyUsb { .. } => ErrorKind::Other,
+            Error::EchoMismatch { .. } | Error::Crc | Error::VerifyFailed { .. } => {
+                ErrorKind::InvalidData
+            }
+        };
+        std::io::Error::new(kind, value)
     }
 }
 
 impl From<std::io::Error> for Error {
     fn from(value: std::io::Error) -> Self {
+        let value = match value.downcast::<Error>() {
+            Ok(error) => return error,
+            Err(value) => value,
+        };
         match value.kind() {
             std::io::ErrorKind::InvalidInput => Error::Usb(rusb::Error::InvalidParam),
             std::io::ErrorKind::PermissionDenied => Error::Usb(rusb::Error::Access),
@@ -42,7 +149,40 @@ impl From<std::io::Error> for Error {
             std::io::ErrorKind::Interrupted => Error::Usb(rusb::Error::Interrupted),
             std::io::ErrorKind::OutOfMemory => Error::Usb(rusb::Error::NoMem),
             std::io::ErrorKind::NotConnected => Error::Nack,
+            std::io::ErrorKind::ConnectionAborted => Error::DataNack,
             _ => Error::Usb(rusb::Error::Other),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_io_error_round_trip() {
+        let errors = || {
+            [
+                Error::Usb(rusb::Error::NotSupported),
+                Error::DataNack,
+                Error::MultipleDevices { count: 2 },
+                Error::NotI2cTinyUsb {
+                    source: Box::new(Error::Crc),
+                },
+            ]
+        };
+        for (error, expected) in errors().into_iter().zip(errors()) {
+            assert_eq!(Error::from(std::io::Error::from(error)), expected);
+        }
+        let io_error = std::io::Error::from(Error::MultipleDevices { count: 2 });
+        assert_eq!(io_error.kind(), std::io::ErrorKind::Other);
+        assert_eq!(
+            io_error.to_string(),
+            "2 i2c-tiny-usb devices found, expected a single one"
+        );
+        assert_eq!(
+            Error::from(std::io::Error::from(std::io::ErrorKind::TimedOut)),
+            Error::Usb(rusb::Error::Timeout)
+        );
+    }
+}
@@ -0,0 +1,94 @@
+//! Notifications about adapters being connected and disconnected, enabled with the `hotplug`
+//! feature.
+
+use rusb::{Device, GlobalContext, Hotplug, HotplugBuilder, Registration, UsbContext};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use crate::{is_supported_device, Result};
+
+/// Event passed to the callback of [`watch_devices`].
+#[derive(Debug)]
+pub enum HotplugEvent {
+    Arrived(Device<GlobalContext>),
+    Left(Device<GlobalContext>),
+}
+
+struct Callback<F>(F);
+
+impl<F: FnMut(HotplugEvent) + Send> Hotplug<GlobalContext> for Callback<F> {
+    fn device_arrived(&mut self, device: Device<GlobalContext>) {
+        if is_supported_device(&device) {
+            (self.0)(HotplugEvent::Arrived(device));
+        }
+    }
+
+    fn device_left(&mut self, device: Device<GlobalContext>) {
+        if is_supported_device(&device) {
+            (self.0)(HotplugEvent::Left(device));
+        }
+    }
+}
+
+/// Keeps delivering events to the callback passed to [`watch_devices`] until dropped.
+pub struct DeviceWatcher {
+    registration: Option<Registration<GlobalContext>>,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Drop for DeviceWatcher {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        drop(self.registration.take());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Calls `callback` whenever a supported adapter is connected or disconnected. Adapters that are
+/// already connected are reported as arrived right away. Events are handled on a background
+/// thread that runs until the returned [`DeviceWatcher`] is dropped.
+///
+/// libusb doesn't allow synchronous transfers from within the callback so devices must not be
+/// opened there. Send the event to another thread instead, e.g. through a channel, and use
+/// [`crate::I2c::open`] or [`crate::I2c::open_by_serial`] from there.
+///
+/// Returns `rusb::Error::NotSupported` if libusb has no hotplug support on this platform.
+pub fn watch_devices<F>(callback: F) -> Result<DeviceWatcher>
+where
+    F: FnMut(HotplugEvent) + Send + 'static,
+{
+    if !rusb::has_hotplug() {
+        return Err(rusb::Error::NotSupported.into());
+    }
+    let context = GlobalContext::default();
+    let registration = HotplugBuilder::new()
+        .enumerate(true)
+        .register(context, Box::new(Callback(callback)))?;
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let thread = {
+        let stop = stop.clone();
+        std::thread::spawn(move || {
+            while !stop.load(Ordering::Relaxed) {
+                // the timeout bounds how long dropping the watcher can take
+                if context
+                    .handle_events(Some(Duration::from_millis(100)))
+                    .is_err()
+                {
+                    break;
+                }
+            }
+        })
+    };
+
+    Ok(DeviceWatcher {
+        registration: Some(registration),
+        stop,
+        thread: Some(thread),
+    })
+}
@@ -14,7 +14,7 @@ use i2c::{Address, BulkTransfer};
 use serial_test::serial;
 use std::io::{Read, Write};
 
-use crate::{Error, I2c};
+use crate::{messages, Error, I2c};
 
 /// Only connects to the device and initializes the interface. Internally, [`crate::I2c`] will read
 /// the functionality and perform an echo test.
@@ -29,22 +29,13 @@ pub fn test_connect() {
 #[test]
 #[serial(device)]
 pub fn test_nack() {
-    use i2c::Message;
     let mut bus = I2c::open_single_device().unwrap();
 
     let mut read_buf = [0u8; 2];
-    let read_res = bus.i2c_transfer(&mut [Message::Read {
-        address: 0x03,
-        data: &mut read_buf,
-        flags: Default::default(),
-    }]);
+    let read_res = bus.i2c_transfer(&mut [messages::read(0x03, &mut read_buf)]);
     assert_eq!(read_res, Err(Error::Nack));
 
-    let write_res = bus.i2c_transfer(&mut [Message::Write {
-        address: 0x03,
-        data: &[0, 1, 2, 3],
-        flags: Default::default(),
-    }]);
+    let write_res = bus.i2c_transfer(&mut [messages::write(0x03, &[0, 1, 2, 3])]);
     assert_eq!(write_res, Err(Error::Nack));
 }
 
@@ -75,16 +66,11 @@ pub fn test_nack_std_io() {
 #[test]
 #[serial(device)]
 pub fn test_general_call() {
-    use i2c::Message;
     let mut bus = I2c::open_single_device().unwrap();
     let mut read_buf = [];
 
     // test using the BulkTransfer interface
-    let read_res = bus.i2c_transfer(&mut [Message::Read {
-        address: 0x00,
-        data: &mut read_buf,
-        flags: Default::default(),
-    }]);
+    let read_res = bus.i2c_transfer(&mut [messages::read(0x00, &mut read_buf)]);
     assert!(read_res.is_ok());
 
     // test using std::io::Read interface
@@ -101,24 +87,17 @@ const EEPROM_TEST_PATTERN: [u8; 16] = [
 
 #[cfg(feature = "hw-tests-program-eeprom")]
 fn pre_program_eeprom(bus: &mut I2c<impl rusb::UsbContext>) {
-    use i2c::Message;
-
     let mut data = [0u8; 17];
     data[0] = 0x20;
     data[1..].copy_from_slice(&EEPROM_TEST_PATTERN);
 
-    bus.i2c_transfer(&mut [Message::Write {
-        address: 0x50,
-        data: &data,
-        flags: Default::default(),
-    }])
-    .unwrap();
+    bus.i2c_transfer(&mut [messages::write(0x50, &data)])
+        .unwrap();
 }
 
 #[test]
 #[serial(device)]
 pub fn test_eeprom_read() {
-    use i2c::Message;
     let mut bus = I2c::open_single_device().unwrap();
 
     #[cfg(feature = "hw-tests-program-eeprom")]
@@ -128,16 +107,8 @@ pub fn test_eeprom_read() {
     let mut read_buf = [0u8; 0x40];
     for off in &[0x00, 0x20] {
         bus.i2c_transfer(&mut [
-            Message::Write {
-                address: 0x50,
-                data: &[0x20 - 8],
-                flags: Default::default(),
-            },
-            Message::Read {
-                address: 0x50,
-                data: &mut read_buf[*off..*off + 0x20],
-                flags: Default::default(),
-            },
+            messages::write(0x50, &[0x20 - 8]),
+            messages::read(0x50, &mut read_buf[*off..*off + 0x20]),
         ])
         .unwrap();
     }
@@ -175,7 +146,8 @@ pub fn test_error_recovery() {
     bus.set_slave_address(0x50, false).unwrap();
     check_valid_read(&mut bus);
 
-    // reads of 128 bytes or more seem to fail
+    // reads of 128 bytes or more seem to fail unless they're split into smaller transfers
+    bus.set_max_transfer_len(256).unwrap();
     let mut big_buf = [0u8; 256];
     bus.write_all(&[0u8]).unwrap();
     assert!(bus.read_exact(&mut big_buf[..128]).is_err());
@@ -193,10 +165,10 @@ pub fn test_eeprom_big_reads() {
     #[cfg(feature = "hw-tests-program-eeprom")]
     pre_program_eeprom(&mut bus);
 
-    // FIXME: on macOS with a i2c-star firmware device, reads of 102 or bigger time out and reads
-    // 128 or bigger fail immediately. Afterwards, the device gets stuck in an error state and
-    // requires cable unplug/replug. Is this a firmware/library bug or our problem?
-    const MAX_READ: usize = 101;
+    // On macOS with a i2c-star firmware device, single control transfers of 102 or more bytes time
+    // out and 128 or more fail immediately. Afterwards, the device gets stuck in an error state
+    // and requires cable unplug/replug. Big reads only work because they're split into chunks.
+    const MAX_READ: usize = 256;
 
     for size in 16..=MAX_READ {
         bus.set_slave_address(0x50, false).unwrap();
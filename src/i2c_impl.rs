@@ -1,37 +1,1334 @@
-use crate::{error::*, protocol};
+use crate::trace::Traced;
+use crate::{
+    error::*, protocol, Connection, Firmware, FunctionFlags, OwnedMessage, RawSequence,
+    ReservedAddressPolicy, ScanResult, TimingStats, TraceHook,
+};
 use rusb::{Device, DeviceHandle, GlobalContext, UsbContext};
+use std::cell::RefCell;
 use std::io::{Read, Write};
+use std::ops::RangeInclusive;
+use std::time::{Duration, Instant};
+
+/// Identification of the USB adapter, see [`I2c::device_info`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceInfo {
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub bus_number: u8,
+    pub address: u8,
+    /// Serial number string or `None` if the device doesn't have one or it couldn't be read.
+    pub serial: Option<String>,
+}
+
+/// Alternate setting of an interface of the active USB configuration, see [`I2c::interfaces`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InterfaceInfo {
+    /// Interface number as passed to [`I2c::open_interface`].
+    pub number: u8,
+    pub alt_setting: u8,
+    pub class_code: u8,
+    pub sub_class_code: u8,
+    pub protocol_code: u8,
+    pub endpoints: Vec<EndpointInfo>,
+}
+
+/// Endpoint of an [`InterfaceInfo`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EndpointInfo {
+    /// Endpoint address including the direction bit.
+    pub address: u8,
+    pub direction: rusb::Direction,
+    pub transfer_type: rusb::TransferType,
+    pub max_packet_size: u16,
+}
+
+/// Summary of the adapter state returned by [`I2c::status_report`]. The [`Display`]
+/// implementation formats it as one `name: value` line per field.
+///
+/// [`Display`]: std::fmt::Display
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StatusReport {
+    pub functionality: FunctionFlags,
+    pub supported_flags: (i2c::ReadFlags, i2c::WriteFlags),
+    /// Bus delay in microseconds or `None` if it wasn't changed from the firmware default.
+    pub delay_us: Option<u16>,
+    /// Raw status byte or `None` if reading it failed.
+    pub last_status: Option<u8>,
+}
+
+impl std::fmt::Display for StatusReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "functionality: {:#010x}", self.functionality.0)?;
+        writeln!(f, "read flags: {:?}", self.supported_flags.0)?;
+        writeln!(f, "write flags: {:?}", self.supported_flags.1)?;
+        match self.delay_us {
+            Some(delay_us) => writeln!(f, "bus delay: {}us", delay_us)?,
+            None => writeln!(f, "bus delay: default")?,
+        }
+        match self.last_status {
+            Some(status) => write!(f, "last status: {}", status),
+            None => write!(f, "last status: unknown"),
+        }
+    }
+}
+
+/// Step of [`I2c::clear_error_state`] after which the adapter responded again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecoveryStep {
+    /// Reading the status of the last I2C operation.
+    StatusRead,
+    /// Resetting the USB port.
+    UsbReset,
+    /// Releasing and claiming the USB interface again.
+    Reclaim,
+}
+
+/// Description of a read performed by [`I2c::transfer_reads`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReadSpec {
+    pub address: u16,
+    pub len: usize,
+    pub flags: i2c::ReadFlags,
+}
+
+/// The USB device or a custom [`Connection`] used to communicate with the adapter.
+enum Transport<T: UsbContext> {
+    Usb(DeviceHandle<T>),
+    Custom(Box<dyn Connection + Send>),
+}
+
+impl<T: UsbContext> Connection for Transport<T> {
+    fn read_control(
+        &self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        buf: &mut [u8],
+        timeout: Duration,
+    ) -> rusb::Result<usize> {
+        match self {
+            Transport::Usb(handle) => {
+                handle.read_control(request_type, request, value, index, buf, timeout)
+            }
+            Transport::Custom(conn) => {
+                conn.read_control(request_type, request, value, index, buf, timeout)
+            }
+        }
+    }
+
+    fn write_control(
+        &self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        buf: &[u8],
+        timeout: Duration,
+    ) -> rusb::Result<usize> {
+        match self {
+            Transport::Usb(handle) => {
+                handle.write_control(request_type, request, value, index, buf, timeout)
+            }
+            Transport::Custom(conn) => {
+                conn.write_control(request_type, request, value, index, buf, timeout)
+            }
+        }
+    }
+}
 
 pub struct I2c<T: UsbContext> {
-    device_handle: DeviceHandle<T>,
+    transport: Transport<T>,
+    functionality: FunctionFlags,
+    firmware: Firmware,
     supported_flags: (i2c::ReadFlags, i2c::WriteFlags),
     address: u16,
+    delay_us: Option<u16>,
+    transfer_options: protocol::TransferOptions,
+    interface: u8,
+    interface_claimed: bool,
+    kernel_driver_detached: bool,
+    trace_hook: Option<TraceHook>,
+    timing: Option<RefCell<TimingStats>>,
+    io_flags: (i2c::ReadFlags, i2c::WriteFlags),
+    read_only: bool,
 }
 
 impl<T: UsbContext> I2c<T> {
+    /// Opens and initializes the given device. This can be used with devices enumerated using a
+    /// custom [`rusb::Context`] rather than the global one, e.g. filtered with
+    /// [`crate::is_supported_device`].
     #[inline]
-    fn open(device: &Device<T>) -> Result<Self> {
-        let device_handle = device.open()?;
-        device_handle.claim_interface(0)?;
-        let supported_flags = protocol::check_device(&device_handle)?;
-        Ok(Self {
-            device_handle,
+    pub fn open(device: &Device<T>) -> Result<Self> {
+        Self::open_interface(device, 0)
+    }
+
+    /// Like [`I2c::open`] but claims the USB interface number `interface` instead of 0. This is
+    /// needed for composite devices that provide the i2c-tiny-usb function next to other ones,
+    /// e.g. adapters combined with a serial port in a single microcontroller. Plain i2c-tiny-usb
+    /// adapters only have interface 0. The requests themselves are the same on any interface.
+    pub fn open_interface(device: &Device<T>, interface: u8) -> Result<Self> {
+        Self::from_handle(device.open()?, interface)
+    }
+
+    fn from_handle(device_handle: DeviceHandle<T>, interface: u8) -> Result<Self> {
+        // the struct is created first so that `Drop` cleans up if any of the steps below fail
+        let mut i2c = Self {
+            transport: Transport::Usb(device_handle),
+            functionality: Default::default(),
+            firmware: Firmware::Unknown,
+            supported_flags: Default::default(),
+            address: 0u16,
+            delay_us: None,
+            transfer_options: Default::default(),
+            interface,
+            interface_claimed: false,
+            kernel_driver_detached: false,
+            trace_hook: None,
+            timing: None,
+            io_flags: Default::default(),
+            read_only: false,
+        };
+        i2c.initialize()?;
+        let desc = i2c.usb_handle()?.device().device_descriptor()?;
+        i2c.firmware = Firmware::from_vendor_product(desc.vendor_id(), desc.product_id());
+        i2c.transfer_options.max_read_len = i2c.firmware.default_max_read_len();
+        Ok(i2c)
+    }
+
+    /// Creates an [`I2c`] from an opened device without checking it, unlike [`I2c::open`] which
+    /// performs several round-trips to verify the adapter. The caller is responsible for having
+    /// validated the device and claimed interface 0, which is released when the [`I2c`] is
+    /// dropped. `supported_flags` are the message flags accepted by transfers and
+    /// [`I2c::functionality`] reports no functions.
+    pub fn from_handle_unchecked(
+        device_handle: DeviceHandle<T>,
+        supported_flags: (i2c::ReadFlags, i2c::WriteFlags),
+    ) -> Self {
+        let firmware = device_handle
+            .device()
+            .device_descriptor()
+            .map(|desc| Firmware::from_vendor_product(desc.vendor_id(), desc.product_id()))
+            .unwrap_or(Firmware::Unknown);
+        Self {
+            transport: Transport::Usb(device_handle),
+            functionality: Default::default(),
+            firmware,
             supported_flags,
             address: 0u16,
+            delay_us: None,
+            transfer_options: protocol::TransferOptions {
+                max_read_len: firmware.default_max_read_len(),
+                ..Default::default()
+            },
+            interface: 0,
+            interface_claimed: true,
+            kernel_driver_detached: false,
+            trace_hook: None,
+            timing: None,
+            io_flags: Default::default(),
+            read_only: false,
+        }
+    }
+
+    /// Claims the interface and checks the device.
+    fn initialize(&mut self) -> Result<()> {
+        // On Linux, the kernel i2c-tiny-usb driver may be bound to the device in which case the
+        // interface cannot be claimed until it's detached.
+        #[cfg(feature = "detach-kernel-driver")]
+        if self
+            .usb_handle()?
+            .kernel_driver_active(self.interface)
+            .unwrap_or(false)
+        {
+            self.usb_handle()?.detach_kernel_driver(self.interface)?;
+            self.kernel_driver_detached = true;
+        }
+
+        self.usb_handle()?.claim_interface(self.interface)?;
+        self.interface_claimed = true;
+        // the interface is released by `Drop` if opening fails
+        self.check()
+    }
+
+    /// Returns the USB device handle or `rusb::Error::NotSupported` if a custom connection is used.
+    fn usb_handle(&self) -> Result<&DeviceHandle<T>> {
+        match &self.transport {
+            Transport::Usb(handle) => Ok(handle),
+            Transport::Custom(_) => Err(rusb::Error::NotSupported.into()),
+        }
+    }
+
+    /// Checks that the device responds like an i2c-tiny-usb adapter and reads its functionality.
+    fn check(&mut self) -> Result<()> {
+        // a device with the IDs of a supported adapter that fails the check is most likely an
+        // unrelated product
+        self.functionality = protocol::check_device(&self.connection()).map_err(|e| match e {
+            Error::Usb(rusb::Error::NoDevice) => e,
+            e => Error::NotI2cTinyUsb {
+                source: Box::new(e),
+            },
+        })?;
+        self.supported_flags = self.functionality.transfer_flags();
+        Ok(())
+    }
+
+    /// Opens the device for inspection without claiming its interface or detaching the kernel
+    /// driver, e.g. to query the functionality and status while the Linux i2c-tiny-usb driver is
+    /// bound. Only the echo, functionality and status requests are sent; I2C transfers and any
+    /// other requests that change the state of the adapter fail with `rusb::Error::Access`. Some
+    /// platforms don't allow requests to an unclaimed interface, in which case opening fails.
+    pub fn open_readonly(device: &Device<T>) -> Result<Self> {
+        let mut i2c = Self::from_handle_unchecked(device.open()?, Default::default());
+        i2c.interface_claimed = false;
+        i2c.read_only = true;
+        i2c.check()?;
+        Ok(i2c)
+    }
+
+    /// Resets the USB device and initializes it again as if it was reopened, which may help when
+    /// the adapter stopped responding. The configuration of this [`I2c`] such as the slave address
+    /// and bus delay is kept and applied to the device again. Not supported with a custom
+    /// connection and fails with `rusb::Error::Access` if opened with [`I2c::open_readonly`].
+    pub fn reset(&mut self) -> Result<()> {
+        if self.read_only {
+            return Err(rusb::Error::Access.into());
+        }
+        if self.interface_claimed {
+            self.interface_claimed = false;
+            self.usb_handle()?.release_interface(self.interface)?;
+        }
+        self.usb_handle()?.reset()?;
+        self.initialize()?;
+        self.restore_delay()
+    }
+
+    /// Tries to bring back an adapter that stopped responding, e.g. the i2c-star firmware after a
+    /// read that was too long, without replugging it. Increasingly disruptive steps are tried in
+    /// the order of [`RecoveryStep`] until the echo test passes and the successful step is
+    /// returned. If none of them helps, the error of the last attempt is returned. The bus delay
+    /// is applied again after a reset. Fails with `rusb::Error::Access` if opened with
+    /// [`I2c::open_readonly`] since the later steps take over the interface.
+    pub fn clear_error_state(&mut self) -> Result<RecoveryStep> {
+        if self.read_only {
+            return Err(rusb::Error::Access.into());
+        }
+        let _ = protocol::get_status(&self.connection());
+        if protocol::echo_test(&self.connection()).is_ok() {
+            return Ok(RecoveryStep::StatusRead);
+        }
+
+        let _ = self.usb_handle()?.reset();
+        if protocol::echo_test(&self.connection()).is_ok() {
+            self.restore_delay()?;
+            return Ok(RecoveryStep::UsbReset);
+        }
+
+        if self.interface_claimed {
+            self.interface_claimed = false;
+            let _ = self.usb_handle()?.release_interface(self.interface);
+        }
+        self.usb_handle()?.claim_interface(self.interface)?;
+        self.interface_claimed = true;
+        protocol::echo_test(&self.connection())?;
+        self.restore_delay()?;
+        Ok(RecoveryStep::Reclaim)
+    }
+
+    /// Sends the bus delay to the device again if it was changed from the default.
+    fn restore_delay(&self) -> Result<()> {
+        if let Some(delay_us) = self.delay_us {
+            protocol::set_delay(&self.connection(), delay_us)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the connection used for all control transfers, which calls the trace hook if set.
+    fn connection(&self) -> Traced<'_, Transport<T>> {
+        Traced {
+            inner: &self.transport,
+            hook: self.trace_hook.as_ref(),
+            timing: self.timing.as_ref(),
+            read_only: self.read_only,
+        }
+    }
+
+    /// Sets a function called after every USB control transfer sent to the adapter, e.g. to
+    /// display the traffic in a monitoring tool. Pass `None` to remove it.
+    pub fn set_trace_hook(&mut self, hook: Option<TraceHook>) {
+        self.trace_hook = hook;
+    }
+
+    /// Starts measuring the duration of every USB control transfer sent to the adapter, e.g. to
+    /// tell whether slow transfers are caused by the bus or by USB latency. Previously collected
+    /// measurements are discarded.
+    pub fn enable_timing(&mut self) {
+        self.timing = Some(RefCell::new(TimingStats::default()));
+    }
+
+    /// Returns the durations measured since [`I2c::enable_timing`] was called or `None` if it
+    /// wasn't.
+    pub fn timing_stats(&self) -> Option<TimingStats> {
+        self.timing.as_ref().map(|timing| timing.borrow().clone())
+    }
+
+    /// Releases the claimed USB interface and closes the device. Dropping the [`I2c`] does the
+    /// same but ignores any errors.
+    pub fn close(mut self) -> Result<()> {
+        if self.interface_claimed {
+            self.interface_claimed = false;
+            self.usb_handle()?.release_interface(self.interface)?;
+        }
+        if self.kernel_driver_detached {
+            self.kernel_driver_detached = false;
+            self.usb_handle()?.attach_kernel_driver(self.interface)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the USB identification of the adapter. Not supported with a custom connection.
+    pub fn device_info(&self) -> Result<DeviceInfo> {
+        let handle = self.usb_handle()?;
+        let device = handle.device();
+        let desc = device.device_descriptor()?;
+        Ok(DeviceInfo {
+            vendor_id: desc.vendor_id(),
+            product_id: desc.product_id(),
+            bus_number: device.bus_number(),
+            address: device.address(),
+            serial: handle.read_serial_number_string_ascii(&desc).ok(),
         })
     }
+
+    /// Returns the number of the active USB configuration.
+    pub fn active_config(&self) -> Result<u8> {
+        Ok(self.usb_handle()?.active_configuration()?)
+    }
+
+    /// Lists the interfaces of the active USB configuration with all their alternate settings,
+    /// e.g. to find the one to pass to [`I2c::open_interface`] on composite devices. The
+    /// i2c-tiny-usb protocol itself only uses control transfers so the adapter's interface
+    /// normally has no endpoints.
+    pub fn interfaces(&self) -> Result<Vec<InterfaceInfo>> {
+        let config = self.usb_handle()?.device().active_config_descriptor()?;
+        Ok(config
+            .interfaces()
+            .flat_map(|interface| interface.descriptors())
+            .map(|desc| InterfaceInfo {
+                number: desc.interface_number(),
+                alt_setting: desc.setting_number(),
+                class_code: desc.class_code(),
+                sub_class_code: desc.sub_class_code(),
+                protocol_code: desc.protocol_code(),
+                endpoints: desc
+                    .endpoint_descriptors()
+                    .map(|endpoint| EndpointInfo {
+                        address: endpoint.address(),
+                        direction: endpoint.direction(),
+                        transfer_type: endpoint.transfer_type(),
+                        max_packet_size: endpoint.max_packet_size(),
+                    })
+                    .collect(),
+            })
+            .collect())
+    }
+
+    /// Returns the product name from the USB string descriptor, e.g. for listing adapters in a
+    /// user interface. Returns `None` if the device doesn't provide it or it couldn't be read.
+    pub fn product_string(&self) -> Option<String> {
+        self.read_string_descriptor(|desc| desc.product_string_index())
+    }
+
+    /// Returns the manufacturer name from the USB string descriptor or `None` if the device
+    /// doesn't provide it or it couldn't be read.
+    pub fn manufacturer_string(&self) -> Option<String> {
+        self.read_string_descriptor(|desc| desc.manufacturer_string_index())
+    }
+
+    /// Reads the string descriptor whose index is selected from the device descriptor by `index`.
+    /// The first language reported by the device is used so that non-ASCII characters are kept,
+    /// with a fallback to the ASCII-only variant for devices with broken language descriptors.
+    fn read_string_descriptor(
+        &self,
+        index: impl Fn(&rusb::DeviceDescriptor) -> Option<u8>,
+    ) -> Option<String> {
+        let handle = self.usb_handle().ok()?;
+        let index = index(&handle.device().device_descriptor().ok()?)?;
+        handle
+            .read_languages(protocol::TIMEOUT)
+            .ok()
+            .and_then(|languages| languages.first().copied())
+            .and_then(|language| {
+                handle
+                    .read_string_descriptor(language, index, protocol::TIMEOUT)
+                    .ok()
+            })
+            .or_else(|| handle.read_string_descriptor_ascii(index).ok())
+    }
+
+    /// Returns the USB speed the adapter is connected with. Each I2C operation takes several
+    /// control transfers so the latency of the link usually limits the throughput more than the
+    /// I2C clock. Returns `rusb::Speed::Unknown` with a custom connection.
+    pub fn usb_speed(&self) -> rusb::Speed {
+        match &self.transport {
+            Transport::Usb(handle) => handle.device().speed(),
+            Transport::Custom(_) => rusb::Speed::Unknown,
+        }
+    }
+
+    /// Returns the slave address set with [`i2c::Address::set_slave_address`], used by the
+    /// [`Read`] and [`Write`] implementations and the register helpers.
+    pub fn slave_address(&self) -> u16 {
+        self.address
+    }
+
+    /// Runs `f` with the slave address temporarily set to `addr` and restores the previous one
+    /// afterwards. Invalid addresses are reported by the transfers performed in `f`.
+    pub fn with_address<R>(&mut self, addr: u16, f: impl FnOnce(&mut Self) -> R) -> R {
+        let previous = std::mem::replace(&mut self.address, addr);
+        let result = f(self);
+        self.address = previous;
+        result
+    }
+
+    /// Returns the adapter type detected from the USB IDs. Defaults such as the maximum transfer
+    /// length are chosen based on it and can be overridden with the corresponding setters.
+    pub fn firmware(&self) -> Firmware {
+        self.firmware
+    }
+
+    /// Returns the functionality bitmask reported by the device when it was opened.
+    pub fn functionality(&self) -> FunctionFlags {
+        self.functionality
+    }
+
+    /// Scans the bus for peripherals by attempting a zero-length read at each address in `range`.
+    /// Returns the addresses that acknowledged. Errors other than a missing acknowledgement abort
+    /// the scan.
+    pub fn scan(&mut self, range: RangeInclusive<u16>) -> Result<Vec<u16>> {
+        protocol::scan(&self.connection(), &self.transfer_options, range)
+    }
+
+    /// Like [`I2c::scan`] but probes with zero-length writes and skips reading the status when
+    /// the adapter already rejected the probe, which needs about half the USB round-trips on a
+    /// mostly empty bus. A USB stall is treated as a missing peripheral rather than an error.
+    /// Settings like the timeout, retries and reserved address policy apply as for
+    /// [`I2c::scan`]; see `examples/bench-scan.rs` for a comparison of the two.
+    pub fn scan_fast(&mut self, range: RangeInclusive<u16>) -> Result<Vec<u16>> {
+        protocol::scan_fast(&self.connection(), &self.transfer_options, range)
+    }
+
+    /// Like [`I2c::scan`] but probes every address even if some of them fail and returns the
+    /// result for each address.
+    pub fn scan_detailed(&mut self, range: RangeInclusive<u16>) -> Vec<(u16, ScanResult)> {
+        protocol::scan_detailed(&self.connection(), &self.transfer_options, range)
+    }
+
+    /// Checks whether a peripheral acknowledges `addr` using a zero-length read, the same probe as
+    /// used by [`I2c::scan`]. USB errors are propagated.
+    pub fn probe(&mut self, addr: u16) -> Result<bool> {
+        self.smbus_quick(addr, true)
+    }
+
+    /// Sends an SMBus "quick" command to `addr`: a zero-length read if `read` is set or a
+    /// zero-length write otherwise. Returns whether the address was acknowledged. Some write-only
+    /// peripherals don't acknowledge reads, so probing them needs the write direction.
+    pub fn smbus_quick(&mut self, addr: u16, read: bool) -> Result<bool> {
+        protocol::smbus_quick(&self.connection(), &self.transfer_options, addr, read)
+    }
+
+    /// Writes `data` to the general call address 0x00, e.g. `&[0x06]` for a software reset of all
+    /// peripherals supporting it. Returns [`Error::Nack`] if no peripheral acknowledged the call.
+    pub fn general_call(&mut self, data: &[u8]) -> Result<()> {
+        protocol::general_call(&self.connection(), &self.transfer_options, data)
+    }
+
+    /// Best-effort attempt to free a bus where a peripheral is stuck holding SDA low, e.g. after a
+    /// read was interrupted. Returns an error if the bus still appears to be stuck. This can't help
+    /// if the adapter itself stopped responding.
+    pub fn recover_bus(&mut self) -> Result<()> {
+        protocol::recover_bus(&self.connection(), &self.transfer_options)
+    }
+
+    /// Repeats the echo test performed when the device is opened. This can be used to check that
+    /// the USB link still works without reopening the device.
+    pub fn self_test(&self) -> Result<()> {
+        protocol::echo_test(&self.connection())
+    }
+
+    /// Sends `value` with the echo command and returns the value the adapter sent back, which
+    /// should be the same. A single step of [`I2c::self_test`] for custom diagnostics.
+    pub fn echo(&self, value: u16) -> Result<u16> {
+        protocol::echo(&self.connection(), value)
+    }
+
+    /// Sends `iterations` pseudo-random values through the echo command and checks that they come
+    /// back unchanged. More thorough than [`I2c::self_test`], e.g. for qualifying cables. The
+    /// first mismatch is reported as [`Error::EchoMismatch`].
+    pub fn echo_stress(&self, iterations: usize) -> Result<()> {
+        let seed = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|t| t.subsec_nanos())
+            .unwrap_or(1);
+        protocol::echo_stress(&self.connection(), iterations, seed)
+    }
+
+    /// Collects the state of the adapter known to the library, e.g. for diagnostic output. Reads
+    /// the status byte from the device, see [`I2c::get_status`].
+    pub fn status_report(&self) -> StatusReport {
+        StatusReport {
+            functionality: self.functionality,
+            supported_flags: self.supported_flags,
+            delay_us: self.delay_us,
+            last_status: self.get_status().ok(),
+        }
+    }
+
+    /// Sends a vendor-specific control request to the adapter and reads the response into `buf`,
+    /// returning the number of bytes received. This is an escape hatch for custom firmware
+    /// commands; sending standard i2c-tiny-usb commands this way may confuse the state of the
+    /// device.
+    pub fn raw_control_read(
+        &self,
+        request: u8,
+        value: u16,
+        index: u16,
+        buf: &mut [u8],
+    ) -> Result<usize> {
+        protocol::raw_control_read(&self.connection(), request, value, index, buf)
+    }
+
+    /// Like [`I2c::raw_control_read`] but sends `buf` to the adapter.
+    pub fn raw_control_write(
+        &self,
+        request: u8,
+        value: u16,
+        index: u16,
+        buf: &[u8],
+    ) -> Result<usize> {
+        protocol::raw_control_write(&self.connection(), request, value, index, buf)
+    }
+
+    /// Reads the raw status byte reported by the device for the last I2C operation: 0 when idle,
+    /// 1 if the address was acknowledged and 2 if it was not. Useful for debugging firmware
+    /// quirks; transfers already check the status and report NACKs as [`Error::Nack`].
+    pub fn get_status(&self) -> Result<u8> {
+        protocol::get_status(&self.connection())
+    }
+
+    /// Sets the delay the device waits between I2C clock edges, i.e. half of the clock period.
+    /// Longer delays slow down the bus which can help with long wires or marginal peripherals.
+    /// The delay is truncated to whole microseconds and must be between 1us and 65535us.
+    pub fn set_bus_delay(&mut self, delay: Duration) -> Result<()> {
+        let delay_us =
+            u16::try_from(delay.as_micros()).map_err(|_| Error::Usb(rusb::Error::InvalidParam))?;
+        protocol::set_delay(&self.connection(), delay_us)?;
+        self.delay_us = Some(delay_us);
+        Ok(())
+    }
+
+    /// Returns the bus delay last set with [`I2c::set_bus_delay`] or [`I2c::set_clock_speed`], or
+    /// `None` if it wasn't changed from the firmware default. The firmware can't report the delay
+    /// so this is the value the device accepted, not one read back from it.
+    pub fn bus_delay(&self) -> Option<Duration> {
+        self.delay_us
+            .map(|delay_us| Duration::from_micros(delay_us.into()))
+    }
+
+    /// Sets the maximum number of bytes received in a single USB control transfer. Some devices
+    /// fail or get stuck on long reads so the default depends on the detected firmware, see
+    /// [`Firmware::default_max_read_len`]. Longer reads are split into multiple messages
+    /// separated by a repeated start, which continues reading from peripherals with an
+    /// auto-incrementing read pointer such as EEPROMs.
+    pub fn set_max_transfer_len(&mut self, len: usize) -> Result<()> {
+        if len == 0 {
+            return Err(rusb::Error::InvalidParam.into());
+        }
+        self.transfer_options.max_read_len = len;
+        Ok(())
+    }
+
+    /// Sets the timeout of the USB control transfers performing I2C I/O and reading its status,
+    /// one second by default. Peripherals that stretch the clock for a long time may need more,
+    /// see also [`I2c::read_with_timeout`] for changing it for a single read.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.transfer_options.timeout = timeout;
+    }
+
+    /// Sets how many times a transfer is repeated when it's not acknowledged, e.g. because of
+    /// spurious NACKs on a noisy bus. The whole message sequence is repeated which is harmless for
+    /// reads but writes may have already been partially applied by the peripheral, so only enable
+    /// this if repeating the writes is safe. Defaults to no retries.
+    pub fn set_retries(&mut self, count: u8) {
+        self.transfer_options.retries = count;
+    }
+
+    /// Sets the time to wait before each retry enabled with [`I2c::set_retries`].
+    pub fn set_retry_backoff(&mut self, backoff: Duration) {
+        self.transfer_options.retry_backoff = backoff;
+    }
+
+    /// Sets whether transfers to reserved addresses are allowed, which is the default. Rejecting
+    /// them guards against unintended general call or 10-bit addressing traffic.
+    pub fn set_reserved_address_policy(&mut self, policy: ReservedAddressPolicy) {
+        self.transfer_options.reserved_address_policy = policy;
+    }
+
+    /// Sets whether adjacent messages of a transfer with the same address, direction and flags are
+    /// merged into one, saving USB round-trips. The merged messages are no longer separated by
+    /// repeated starts, so only enable this for peripherals that don't depend on them. Disabled by
+    /// default.
+    pub fn set_merge_adjacent(&mut self, enabled: bool) {
+        self.transfer_options.merge_adjacent = enabled;
+    }
+
+    /// Sets whether the status is read from the device after each message to detect NACKs, which
+    /// is the default. Disabling it halves the number of USB control transfers, which dominate
+    /// the time of transfers made of short messages. The NACK is then only detected if the device
+    /// stalls the control transfer, which not all firmware versions do, and a NACK of the data of a
+    /// write is reported as [`Error::Nack`] instead of [`Error::DataNack`].
+    pub fn set_status_check(&mut self, enabled: bool) {
+        self.transfer_options.check_status = enabled;
+    }
+
+    /// Sets the I2C clock frequency. Since the device only supports whole microsecond delays, the
+    /// actual frequency may be lower than requested.
+    pub fn set_clock_speed(&mut self, hz: u32) -> Result<()> {
+        let delay_us = protocol::delay_for_frequency(hz)?;
+        protocol::set_delay(&self.connection(), delay_us)?;
+        self.delay_us = Some(delay_us);
+        Ok(())
+    }
+
+    /// Same as [`i2c::BulkTransfer::i2c_transfer`] but the error also contains the index of the
+    /// message that failed, which is useful for recovering from partially completed transfers.
+    pub fn transfer_detailed(
+        &mut self,
+        messages: &mut [i2c::Message],
+    ) -> std::result::Result<(), TransferError> {
+        protocol::check_flags(messages, self.supported_flags)?;
+        protocol::transfer_detailed(&self.connection(), &self.transfer_options, messages)
+    }
+
+    /// Performs a transfer where the USB timeout of each message can be overridden by the entry
+    /// at the same index of `timeouts`. Messages with a `None` entry, or without an entry at all,
+    /// use the default timeout. Useful for e.g. a quick write followed by a read that the
+    /// peripheral stretches while performing a measurement.
+    pub fn transfer_with_timeouts(
+        &mut self,
+        messages: &mut [i2c::Message],
+        timeouts: &[Option<Duration>],
+    ) -> Result<()> {
+        protocol::check_flags(messages, self.supported_flags)?;
+        protocol::transfer_with_timeouts(
+            &self.connection(),
+            &self.transfer_options,
+            messages,
+            timeouts,
+        )
+        .map_err(Error::from)
+    }
+
+    /// Writes `write` to the peripheral at `addr` and then reads `read.len()` bytes from it after a
+    /// repeated start, without releasing the bus in between. This is the usual way of reading
+    /// registers or memory from a peripheral.
+    pub fn write_read(&mut self, addr: u16, write: &[u8], read: &mut [u8]) -> Result<()> {
+        protocol::transfer(
+            &self.connection(),
+            &self.transfer_options,
+            &mut [
+                i2c::Message::Write {
+                    address: addr,
+                    data: write,
+                    flags: Default::default(),
+                },
+                i2c::Message::Read {
+                    address: addr,
+                    data: read,
+                    flags: Default::default(),
+                },
+            ],
+        )
+    }
+
+    /// Performs a transfer that has to complete before `deadline`. Each control transfer gets the
+    /// time left until the deadline as its timeout instead of the default one. Returns
+    /// `rusb::Error::Timeout` without sending anything further once the deadline has passed.
+    pub fn transfer_until(
+        &mut self,
+        messages: &mut [i2c::Message],
+        deadline: Instant,
+    ) -> Result<()> {
+        protocol::check_flags(messages, self.supported_flags)?;
+        let options = protocol::TransferOptions {
+            deadline: Some(deadline),
+            ..self.transfer_options.clone()
+        };
+        protocol::transfer(&self.connection(), &options, messages)
+    }
+
+    /// Writes `data` to the peripheral at `addr` as a complete transaction framed by a start and a
+    /// stop condition. A write inside a transfer of several messages is instead followed by a
+    /// repeated start, which some peripherals treat differently, e.g. by not executing a command
+    /// until the bus is released.
+    pub fn write_with_stop(&mut self, addr: u16, data: &[u8]) -> Result<()> {
+        protocol::transfer(
+            &self.connection(),
+            &self.transfer_options,
+            &mut [i2c::Message::Write {
+                address: addr,
+                data,
+                flags: Default::default(),
+            }],
+        )
+    }
+
+    /// Sets the flags of the reads performed by the [`Read`] implementation, e.g. to use `NACK`.
+    /// Returns `rusb::Error::NotSupported` if the device doesn't support them and
+    /// `rusb::Error::InvalidParam` for `NO_START` which is only valid within a transfer.
+    pub fn set_default_read_flags(&mut self, flags: i2c::ReadFlags) -> Result<()> {
+        if flags.contains(i2c::ReadFlags::NO_START) {
+            // every read is a separate transfer so there is no previous message to continue
+            return Err(rusb::Error::InvalidParam.into());
+        }
+        if !self.supported_flags.0.contains(flags) {
+            return Err(rusb::Error::NotSupported.into());
+        }
+        self.io_flags.0 = flags;
+        Ok(())
+    }
+
+    /// Sets the flags of the writes performed by the [`Write`] implementation. Returns
+    /// `rusb::Error::NotSupported` if the device doesn't support them and
+    /// `rusb::Error::InvalidParam` for `NO_START` which is only valid within a transfer.
+    pub fn set_default_write_flags(&mut self, flags: i2c::WriteFlags) -> Result<()> {
+        if flags.contains(i2c::WriteFlags::NO_START) {
+            // every write is a separate transfer so there is no previous message to continue
+            return Err(rusb::Error::InvalidParam.into());
+        }
+        if !self.supported_flags.1.contains(flags) {
+            return Err(rusb::Error::NotSupported.into());
+        }
+        self.io_flags.1 = flags;
+        Ok(())
+    }
+
+    /// Starts building a sequence of reads and writes that can omit the start condition between
+    /// steps, see [`RawSequence`].
+    pub fn raw_sequence(&mut self) -> RawSequence<'_, T> {
+        RawSequence::new(self)
+    }
+
+    /// Returns the message flags supported by the device, same as
+    /// [`i2c::BulkTransfer::i2c_transfer_support`].
+    pub(crate) fn supported_flags(&self) -> (i2c::ReadFlags, i2c::WriteFlags) {
+        self.supported_flags
+    }
+
+    /// Same as [`i2c::BulkTransfer::i2c_transfer`] but always merges adjacent messages, see
+    /// [`I2c::set_merge_adjacent`].
+    #[cfg(feature = "embedded-hal")]
+    pub(crate) fn transfer_merging(&mut self, messages: &mut [i2c::Message]) -> Result<()> {
+        protocol::check_flags(messages, self.supported_flags)?;
+        let options = protocol::TransferOptions {
+            merge_adjacent: true,
+            ..self.transfer_options.clone()
+        };
+        protocol::transfer(&self.connection(), &options, messages)
+    }
+
+    /// Returns `true` if messages with the given flags can be sent to this device. Any non-empty
+    /// flags require [`FunctionFlags::supports_protocol_mangling`].
+    pub fn supports_flags(&self, read: i2c::ReadFlags, write: i2c::WriteFlags) -> bool {
+        self.supported_flags.0.contains(read) && self.supported_flags.1.contains(write)
+    }
+
+    /// Reads up to `buf.len()` bytes from the peripheral at `addr` and returns the number of bytes
+    /// received. Unlike other reads, a device sending less data than requested isn't an error, so
+    /// callers can tell how much of `buf` was filled and don't need to clear it beforehand.
+    pub fn read_partial(&mut self, addr: u16, buf: &mut [u8]) -> Result<usize> {
+        let result = protocol::transfer(
+            &self.connection(),
+            &self.transfer_options,
+            &mut [i2c::Message::Read {
+                address: addr,
+                data: buf,
+                flags: Default::default(),
+            }],
+        );
+        match result {
+            Ok(()) => Ok(buf.len()),
+            Err(Error::ShortTransfer { transferred, .. }) => Ok(transferred),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Reads `buf.len()` bytes from the peripheral at `addr` with a custom USB timeout instead of
+    /// the one set with [`I2c::set_timeout`]. Useful for peripherals that stretch the clock for a
+    /// long time, e.g. while performing a measurement, which keeps the control transfer from
+    /// completing.
+    pub fn read_with_timeout(
+        &mut self,
+        addr: u16,
+        buf: &mut [u8],
+        timeout: Duration,
+    ) -> Result<()> {
+        let options = protocol::TransferOptions {
+            timeout,
+            ..self.transfer_options.clone()
+        };
+        protocol::transfer(
+            &self.connection(),
+            &options,
+            &mut [i2c::Message::Read {
+                address: addr,
+                data: buf,
+                flags: Default::default(),
+            }],
+        )
+    }
+
+    /// Reads `buf.len()` bytes from the peripheral at `addr` followed by a checksum byte, which is
+    /// compared to `crc` computed over the data. Returns [`Error::Crc`] if they don't match. Use
+    /// e.g. [`crate::checksum::crc8_sensirion`] for peripherals with a trailing CRC-8.
+    pub fn read_checked(
+        &mut self,
+        addr: u16,
+        buf: &mut [u8],
+        crc: impl Fn(&[u8]) -> u8,
+    ) -> Result<()> {
+        let mut data = vec![0u8; buf.len() + 1];
+        protocol::transfer(
+            &self.connection(),
+            &self.transfer_options,
+            &mut [i2c::Message::Read {
+                address: addr,
+                data: &mut data,
+                flags: Default::default(),
+            }],
+        )?;
+        let (payload, checksum) = data.split_at(buf.len());
+        if crc(payload) != checksum[0] {
+            return Err(Error::Crc);
+        }
+        buf.copy_from_slice(payload);
+        Ok(())
+    }
+
+    /// Performs a transfer of stored messages. The data read is placed in the read messages.
+    pub fn transfer_owned(&mut self, messages: &mut [OwnedMessage]) -> Result<()> {
+        let mut borrowed: Vec<i2c::Message> =
+            messages.iter_mut().map(OwnedMessage::to_message).collect();
+        i2c::BulkTransfer::i2c_transfer(self, &mut borrowed)
+    }
+
+    /// Performs a transfer consisting only of reads described by `ops` and returns the data of
+    /// each read in the same order. Useful when the lengths are only known at runtime.
+    pub fn transfer_reads(&mut self, ops: &[ReadSpec]) -> Result<Vec<Vec<u8>>> {
+        let mut buffers: Vec<Vec<u8>> = ops.iter().map(|op| vec![0u8; op.len]).collect();
+        let mut messages: Vec<i2c::Message> = ops
+            .iter()
+            .zip(buffers.iter_mut())
+            .map(|(op, data)| i2c::Message::Read {
+                address: op.address,
+                data,
+                flags: op.flags,
+            })
+            .collect();
+        i2c::BulkTransfer::i2c_transfer(self, &mut messages)?;
+        drop(messages);
+        Ok(buffers)
+    }
+
+    /// Reads `src` until the end and writes the data to the peripheral at `addr` as separate
+    /// writes of `chunk` bytes each, except for the last one which may be shorter. Returns the
+    /// total number of bytes written.
+    ///
+    /// This is meant for peripherals taking a stream of data like displays or DACs. No paging is
+    /// done: each write starts from scratch and follows the previous one without a delay, so
+    /// EEPROMs need [`crate::Eeprom::write_stream`] instead.
+    pub fn write_stream(
+        &mut self,
+        addr: u16,
+        mut src: impl Read,
+        chunk: usize,
+    ) -> std::result::Result<u64, WriteStreamError> {
+        let mut written = 0u64;
+        if chunk == 0 {
+            return Err(WriteStreamError {
+                written,
+                source: Error::from(rusb::Error::InvalidParam).into(),
+            });
+        }
+        let mut buf = vec![0u8; chunk];
+        loop {
+            let len = read_full(&mut src, &mut buf).map_err(|e| WriteStreamError {
+                written,
+                source: WriteStreamFailure::Read(e),
+            })?;
+            if len == 0 {
+                return Ok(written);
+            }
+            protocol::transfer(
+                &self.connection(),
+                &self.transfer_options,
+                &mut [i2c::Message::Write {
+                    address: addr,
+                    data: &buf[..len],
+                    flags: Default::default(),
+                }],
+            )
+            .map_err(|e| WriteStreamError {
+                written,
+                source: e.into(),
+            })?;
+            written += len as u64;
+        }
+    }
+
+    /// Performs a transfer consisting only of writes, given as pairs of the peripheral address and
+    /// the data. Saves building a mutable [`i2c::Message`] array when nothing is read.
+    pub fn write_all_messages(&mut self, writes: &[(u16, &[u8])]) -> Result<()> {
+        let mut messages: Vec<i2c::Message> = writes
+            .iter()
+            .map(|&(address, data)| i2c::Message::Write {
+                address,
+                data,
+                flags: Default::default(),
+            })
+            .collect();
+        protocol::transfer(&self.connection(), &self.transfer_options, &mut messages)
+    }
+
+    /// Writes the register number `reg` to the current slave address and reads `buf.len()` bytes
+    /// back after a repeated start.
+    fn register_read(&mut self, reg: u8, buf: &mut [u8]) -> Result<()> {
+        self.write_read(self.address, &[reg], buf)
+    }
+
+    /// Writes the register number `reg` followed by `data` to the current slave address.
+    fn register_write(&mut self, reg: u8, data: &[u8]) -> Result<()> {
+        let mut buf = Vec::with_capacity(data.len() + 1);
+        buf.push(reg);
+        buf.extend_from_slice(data);
+        protocol::transfer(
+            &self.connection(),
+            &self.transfer_options,
+            &mut [i2c::Message::Write {
+                address: self.address,
+                data: &buf,
+                flags: Default::default(),
+            }],
+        )
+    }
+
+    /// Reads a single byte from register `reg` of the current slave (SMBus "read byte data").
+    pub fn read_reg_u8(&mut self, reg: u8) -> Result<u8> {
+        let mut buf = [0u8; 1];
+        self.register_read(reg, &mut buf)?;
+        Ok(buf[0])
+    }
+
+    /// Writes a single byte to register `reg` of the current slave (SMBus "write byte data").
+    pub fn write_reg_u8(&mut self, reg: u8, val: u8) -> Result<()> {
+        self.register_write(reg, &[val])
+    }
+
+    /// Reads `buf.len()` consecutive registers starting at `start_reg` in a single transfer. This
+    /// relies on the peripheral incrementing its register pointer after each byte, as most
+    /// multi-byte sensors do. Long reads are split according to [`I2c::set_max_transfer_len`].
+    pub fn smbus_read_regs(&mut self, start_reg: u8, buf: &mut [u8]) -> Result<()> {
+        self.register_read(start_reg, buf)
+    }
+
+    /// Reads a 16-bit little-endian word from register `reg`. This is the byte order used by SMBus
+    /// "read word data". Same as [`I2c::read_reg_u16_le`].
+    pub fn smbus_read_word_le(&mut self, reg: u8) -> Result<u16> {
+        self.read_reg_u16_le(reg)
+    }
+
+    /// Reads a 16-bit big-endian word from register `reg`. Same as [`I2c::read_reg_u16_be`].
+    pub fn smbus_read_word_be(&mut self, reg: u8) -> Result<u16> {
+        self.read_reg_u16_be(reg)
+    }
+
+    /// Like [`I2c::smbus_read_word_le`] but also reads the packet error code sent by the
+    /// peripheral after the data and returns [`Error::Crc`] if it doesn't match. Needed for
+    /// peripherals that always use PEC such as the MLX90614.
+    pub fn smbus_read_word_pec(&mut self, reg: u8) -> Result<u16> {
+        let mut buf = [0u8; 3];
+        self.register_read(reg, &mut buf)?;
+        let addr_byte = (self.address as u8) << 1;
+        let pec = crate::checksum::crc8_smbus(&[addr_byte, reg, addr_byte | 1, buf[0], buf[1]]);
+        if pec != buf[2] {
+            return Err(Error::Crc);
+        }
+        Ok(u16::from_le_bytes([buf[0], buf[1]]))
+    }
+
+    /// Writes a 16-bit little-endian word to register `reg`. This is the byte order used by SMBus
+    /// "write word data". Same as [`I2c::write_reg_u16_le`].
+    pub fn smbus_write_word_le(&mut self, reg: u8, val: u16) -> Result<()> {
+        self.write_reg_u16_le(reg, val)
+    }
+
+    /// Writes a 16-bit big-endian word to register `reg`. Same as [`I2c::write_reg_u16_be`].
+    pub fn smbus_write_word_be(&mut self, reg: u8, val: u16) -> Result<()> {
+        self.write_reg_u16_be(reg, val)
+    }
+
+    /// Reads `N` bytes starting at register `reg` of the current slave.
+    fn read_reg_array<const N: usize>(&mut self, reg: u8) -> Result<[u8; N]> {
+        let mut buf = [0u8; N];
+        self.register_read(reg, &mut buf)?;
+        Ok(buf)
+    }
+
+    /// Reads a 16-bit big-endian value from register `reg`.
+    pub fn read_reg_u16_be(&mut self, reg: u8) -> Result<u16> {
+        self.read_reg_array(reg).map(u16::from_be_bytes)
+    }
+
+    /// Reads a 16-bit little-endian value from register `reg`.
+    pub fn read_reg_u16_le(&mut self, reg: u8) -> Result<u16> {
+        self.read_reg_array(reg).map(u16::from_le_bytes)
+    }
+
+    /// Reads a 24-bit big-endian value from register `reg`, as used by many pressure sensors and
+    /// ADCs.
+    pub fn read_reg_u24_be(&mut self, reg: u8) -> Result<u32> {
+        let [b0, b1, b2] = self.read_reg_array(reg)?;
+        Ok(u32::from_be_bytes([0, b0, b1, b2]))
+    }
+
+    /// Reads a 24-bit little-endian value from register `reg`.
+    pub fn read_reg_u24_le(&mut self, reg: u8) -> Result<u32> {
+        let [b0, b1, b2] = self.read_reg_array(reg)?;
+        Ok(u32::from_le_bytes([b0, b1, b2, 0]))
+    }
+
+    /// Reads a 32-bit big-endian value from register `reg`.
+    pub fn read_reg_u32_be(&mut self, reg: u8) -> Result<u32> {
+        self.read_reg_array(reg).map(u32::from_be_bytes)
+    }
+
+    /// Reads a 32-bit little-endian value from register `reg`.
+    pub fn read_reg_u32_le(&mut self, reg: u8) -> Result<u32> {
+        self.read_reg_array(reg).map(u32::from_le_bytes)
+    }
+
+    /// Writes a 16-bit big-endian value to register `reg`.
+    pub fn write_reg_u16_be(&mut self, reg: u8, val: u16) -> Result<()> {
+        self.register_write(reg, &val.to_be_bytes())
+    }
+
+    /// Writes a 16-bit little-endian value to register `reg`.
+    pub fn write_reg_u16_le(&mut self, reg: u8, val: u16) -> Result<()> {
+        self.register_write(reg, &val.to_le_bytes())
+    }
+
+    /// Writes a 24-bit big-endian value to register `reg`. Returns `rusb::Error::InvalidParam` if
+    /// `val` doesn't fit into 24 bits.
+    pub fn write_reg_u24_be(&mut self, reg: u8, val: u32) -> Result<()> {
+        if val > 0xff_ffff {
+            return Err(rusb::Error::InvalidParam.into());
+        }
+        self.register_write(reg, &val.to_be_bytes()[1..])
+    }
+
+    /// Writes a 24-bit little-endian value to register `reg`. Returns `rusb::Error::InvalidParam`
+    /// if `val` doesn't fit into 24 bits.
+    pub fn write_reg_u24_le(&mut self, reg: u8, val: u32) -> Result<()> {
+        if val > 0xff_ffff {
+            return Err(rusb::Error::InvalidParam.into());
+        }
+        self.register_write(reg, &val.to_le_bytes()[..3])
+    }
+
+    /// Writes a 32-bit big-endian value to register `reg`.
+    pub fn write_reg_u32_be(&mut self, reg: u8, val: u32) -> Result<()> {
+        self.register_write(reg, &val.to_be_bytes())
+    }
+
+    /// Writes a 32-bit little-endian value to register `reg`.
+    pub fn write_reg_u32_le(&mut self, reg: u8, val: u32) -> Result<()> {
+        self.register_write(reg, &val.to_le_bytes())
+    }
+
+    /// Reads a variable-length block from register `reg` of the current slave (SMBus "block
+    /// read"). The peripheral sends the number of bytes first, which must not exceed 32. Since
+    /// the adapter can't change the length of a read in progress, the register is read twice.
+    pub fn smbus_read_block(&mut self, reg: u8) -> Result<Vec<u8>> {
+        protocol::smbus_read_block(
+            &self.connection(),
+            &self.transfer_options,
+            self.address,
+            reg,
+        )
+    }
+}
+
+/// Returns the only connected supported device or an error if there are none or more than one.
+pub(crate) fn single_device() -> Result<Device<GlobalContext>> {
+    let mut devs = crate::try_devices()?;
+    if devs.is_empty() {
+        return Err(rusb::Error::NoDevice.into());
+    }
+    if devs.len() > 1 {
+        return Err(Error::MultipleDevices { count: devs.len() });
+    }
+    Ok(devs.remove(0))
 }
 
 impl I2c<GlobalContext> {
+    /// Creates an [`I2c`] communicating through a custom [`Connection`] instead of a USB device,
+    /// e.g. a USB/IP client or a shim recording and replaying the traffic. The device is not
+    /// checked, see [`I2c::from_handle_unchecked`]. Methods specific to USB devices such as
+    /// [`I2c::device_info`] return `rusb::Error::NotSupported`.
+    pub fn from_connection(
+        conn: impl Connection + Send + 'static,
+        supported_flags: (i2c::ReadFlags, i2c::WriteFlags),
+    ) -> Self {
+        Self {
+            transport: Transport::Custom(Box::new(conn)),
+            functionality: Default::default(),
+            firmware: Firmware::Unknown,
+            supported_flags,
+            address: 0u16,
+            delay_us: None,
+            transfer_options: Default::default(),
+            interface: 0,
+            interface_claimed: false,
+            kernel_driver_detached: false,
+            trace_hook: None,
+            timing: None,
+            io_flags: Default::default(),
+            read_only: false,
+        }
+    }
+
     pub fn open_single_device() -> Result<Self> {
-        let devs = crate::devices();
-        if devs.is_empty() {
-            return Err(rusb::Error::NoDevice.into());
+        I2c::open(&single_device()?)
+    }
+
+    /// Opens the first supported device, ignoring any others. The order of devices depends on the
+    /// USB enumeration and is not stable across reboots or replugging, so this is only meant for
+    /// interactive use and debugging. Use [`I2c::open_single_device`] or
+    /// [`I2c::open_by_serial`] otherwise.
+    pub fn open_first() -> Result<Self> {
+        match crate::try_devices()?.first() {
+            None => Err(rusb::Error::NoDevice.into()),
+            Some(dev) => I2c::open(dev),
+        }
+    }
+
+    /// Waits until exactly one supported device is connected and opens it. The device list is
+    /// checked every `poll_interval`. Returns `rusb::Error::NoDevice` if no single device appears
+    /// within `timeout`.
+    pub fn wait_for_single_device(timeout: Duration, poll_interval: Duration) -> Result<Self> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let mut devs = crate::devices();
+            if devs.len() == 1 {
+                return I2c::open(&devs.remove(0));
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(rusb::Error::NoDevice.into());
+            }
+            std::thread::sleep(poll_interval.min(deadline - now));
+        }
+    }
+
+    /// Attempts to open every connected supported device. The result contains one entry per device
+    /// so that a failure to open one of them doesn't prevent using the others.
+    pub fn open_all() -> Vec<Result<Self>> {
+        crate::devices().iter().map(I2c::open).collect()
+    }
+
+    /// Opens the supported device with the given USB bus number and address. Useful when multiple
+    /// adapters are connected.
+    pub fn open_by_bus_address(bus: u8, address: u8) -> Result<Self> {
+        match crate::try_devices()?
+            .iter()
+            .find(|dev| dev.bus_number() == bus && dev.address() == address)
+        {
+            None => Err(rusb::Error::NoDevice.into()),
+            Some(dev) => I2c::open(dev),
+        }
+    }
+
+    /// Opens the first USB device with the given vendor and product ID, even if it's not known to
+    /// be an i2c-tiny-usb adapter. The device is still checked when opening it.
+    pub fn open_by_vid_pid(vid: u16, pid: u16) -> Result<Self> {
+        for dev in rusb::devices()?.iter() {
+            match dev.device_descriptor() {
+                Ok(desc) if desc.vendor_id() == vid && desc.product_id() == pid => {
+                    return I2c::open(&dev)
+                }
+                _ => continue,
+            }
+        }
+        Err(rusb::Error::NoDevice.into())
+    }
+
+    /// Opens the first supported device with the given USB serial number string. Devices without
+    /// a serial number descriptor are skipped.
+    pub fn open_by_serial(serial: &str) -> Result<Self> {
+        for dev in crate::try_devices()? {
+            let desc = match dev.device_descriptor() {
+                Err(_) => continue,
+                Ok(x) => x,
+            };
+            let handle = match dev.open() {
+                Err(_) => continue,
+                Ok(x) => x,
+            };
+            match handle.read_serial_number_string_ascii(&desc) {
+                Ok(s) if s == serial => return I2c::from_handle(handle, 0),
+                _ => continue,
+            }
+        }
+        Err(rusb::Error::NoDevice.into())
+    }
+}
+
+impl<T: UsbContext> std::fmt::Debug for I2c<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut s = f.debug_struct("I2c");
+        match &self.transport {
+            Transport::Usb(handle) => {
+                let device = handle.device();
+                if let Ok(desc) = device.device_descriptor() {
+                    s.field("vendor_id", &format_args!("{:04x}", desc.vendor_id()))
+                        .field("product_id", &format_args!("{:04x}", desc.product_id()));
+                }
+                s.field("bus_number", &device.bus_number())
+                    .field("usb_address", &device.address());
+            }
+            Transport::Custom(_) => {
+                s.field("transport", &"custom");
+            }
+        }
+        s.field("slave_address", &format_args!("{:#04x}", self.address))
+            .field("supported_flags", &self.supported_flags)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<T: UsbContext> Drop for I2c<T> {
+    fn drop(&mut self) {
+        // Closing the handle should release the interface as well but that's not reliable on all
+        // platforms so we do it explicitly.
+        let Transport::Usb(handle) = &self.transport else {
+            return;
+        };
+        if self.interface_claimed {
+            let _ = handle.release_interface(self.interface);
         }
-        if devs.len() > 1 {
-            return Err(rusb::Error::Other.into());
+        if self.kernel_driver_detached {
+            let _ = handle.attach_kernel_driver(self.interface);
         }
-        I2c::open(&devs[0])
     }
 }
 
@@ -43,6 +1340,8 @@ impl<T: UsbContext> i2c::Address for I2c<T> {
     fn set_slave_address(&mut self, addr: u16, tenbit: bool) -> Result<()> {
         if tenbit {
             Err(rusb::Error::NotSupported.into())
+        } else if addr > protocol::MAX_ADDRESS {
+            Err(rusb::Error::InvalidParam.into())
         } else {
             self.address = addr;
             Ok(())
@@ -51,13 +1350,19 @@ impl<T: UsbContext> i2c::Address for I2c<T> {
 }
 
 impl<T: UsbContext> Read for I2c<T> {
+    // A read either fills the whole buffer or fails. Returning the length of a short read would
+    // make `read_exact` start another I2C read and append its data, so short control transfers
+    // are reported as errors; `I2c::read_partial` returns the length instead. There is no
+    // variant reading into uninitialized memory since rusb only accepts initialized buffers, and
+    // zeroing them is cheap compared to the USB round-trip of each chunk anyway.
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
         protocol::transfer(
-            &self.device_handle,
+            &self.connection(),
+            &self.transfer_options,
             &mut [i2c::Message::Read {
                 address: self.address,
                 data: buf,
-                flags: Default::default(),
+                flags: self.io_flags.0,
             }],
         )?;
         Ok(buf.len())
@@ -67,11 +1372,12 @@ impl<T: UsbContext> Read for I2c<T> {
 impl<T: UsbContext> Write for I2c<T> {
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
         protocol::transfer(
-            &self.device_handle,
+            &self.connection(),
+            &self.transfer_options,
             &mut [i2c::Message::Write {
                 address: self.address,
                 data: buf,
-                flags: Default::default(),
+                flags: self.io_flags.1,
             }],
         )?;
         Ok(buf.len())
@@ -87,12 +1393,297 @@ impl<T: UsbContext> Write for I2c<T> {
 const fn assert_impl_readwrite<T: i2c::ReadWrite>() {}
 const _: () = assert_impl_readwrite::<I2c<GlobalContext>>();
 
+impl<T: UsbContext> i2c::Smbus for I2c<T> {
+    fn smbus_write_quick(&mut self, value: bool) -> Result<()> {
+        match protocol::smbus_quick(
+            &self.connection(),
+            &self.transfer_options,
+            self.address,
+            value,
+        )? {
+            true => Ok(()),
+            false => Err(Error::Nack),
+        }
+    }
+
+    fn smbus_read_byte(&mut self) -> Result<u8> {
+        let mut buf = [0u8; 1];
+        protocol::transfer(
+            &self.connection(),
+            &self.transfer_options,
+            &mut [i2c::Message::Read {
+                address: self.address,
+                data: &mut buf,
+                flags: Default::default(),
+            }],
+        )?;
+        Ok(buf[0])
+    }
+
+    fn smbus_write_byte(&mut self, value: u8) -> Result<()> {
+        protocol::transfer(
+            &self.connection(),
+            &self.transfer_options,
+            &mut [i2c::Message::Write {
+                address: self.address,
+                data: &[value],
+                flags: Default::default(),
+            }],
+        )
+    }
+
+    fn smbus_read_byte_data(&mut self, command: u8) -> Result<u8> {
+        let mut buf = [0u8; 1];
+        self.register_read(command, &mut buf)?;
+        Ok(buf[0])
+    }
+
+    fn smbus_write_byte_data(&mut self, command: u8, value: u8) -> Result<()> {
+        self.register_write(command, &[value])
+    }
+
+    fn smbus_read_word_data(&mut self, command: u8) -> Result<u16> {
+        self.smbus_read_word_le(command)
+    }
+
+    fn smbus_write_word_data(&mut self, command: u8, value: u16) -> Result<()> {
+        self.smbus_write_word_le(command, value)
+    }
+
+    fn smbus_process_call(&mut self, command: u8, value: u16) -> Result<u16> {
+        let [lo, hi] = value.to_le_bytes();
+        let mut buf = [0u8; 2];
+        self.write_read(self.address, &[command, lo, hi], &mut buf)?;
+        Ok(u16::from_le_bytes(buf))
+    }
+
+    /// Returns `rusb::Error::Overflow` if the block doesn't fit into `value`.
+    fn smbus_read_block_data(&mut self, command: u8, value: &mut [u8]) -> Result<usize> {
+        let block = self.smbus_read_block(command)?;
+        value
+            .get_mut(..block.len())
+            .ok_or(rusb::Error::Overflow)?
+            .copy_from_slice(&block);
+        Ok(block.len())
+    }
+
+    /// Returns `rusb::Error::InvalidParam` if `value` is longer than 32 bytes.
+    fn smbus_write_block_data(&mut self, command: u8, value: &[u8]) -> Result<()> {
+        let len = match u8::try_from(value.len()) {
+            Ok(len) if len <= 32 => len,
+            _ => return Err(rusb::Error::InvalidParam.into()),
+        };
+        let mut buf = Vec::with_capacity(value.len() + 1);
+        buf.push(len);
+        buf.extend_from_slice(value);
+        self.register_write(command, &buf)
+    }
+}
+
 impl<T: UsbContext> i2c::BulkTransfer for I2c<T> {
     fn i2c_transfer_support(&mut self) -> Result<(i2c::ReadFlags, i2c::WriteFlags)> {
         Ok(self.supported_flags)
     }
 
     fn i2c_transfer(&mut self, messages: &mut [i2c::Message]) -> Result<()> {
-        protocol::transfer(&self.device_handle, messages)
+        protocol::check_flags(messages, self.supported_flags)?;
+        protocol::transfer(&self.connection(), &self.transfer_options, messages)
+    }
+}
+
+/// Reads from `src` until `buf` is full or the end of `src` is reached and returns the number of
+/// bytes read.
+pub(crate) fn read_full(src: &mut impl Read, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match src.read(&mut buf[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::mock::MockConnection;
+    use crate::protocol::constants::*;
+
+    #[test]
+    fn test_from_connection() {
+        let dev = MockConnection::new();
+        dev.schedule_read(CMD_GET_STATUS, I2C_M_RD, 0, &[STATUS_ADDRESS_ACK]);
+        dev.schedule_read(CMD_I2C_IO | CMD_I2C_END, I2C_M_RD, 0x50, &[0xaa, 0xbb]);
+        dev.schedule_read(CMD_GET_STATUS, I2C_M_RD, 0, &[STATUS_IDLE]);
+        let mut bus = I2c::from_connection(dev, Default::default());
+        let mut buf = [0u8; 2];
+        bus.write_read(0x50, &[0x00], &mut buf).unwrap();
+        assert_eq!(buf, [0xaa, 0xbb]);
+        assert_eq!(bus.usb_speed(), rusb::Speed::Unknown);
+        assert_eq!(
+            bus.device_info(),
+            Err(Error::Usb(rusb::Error::NotSupported))
+        );
+        assert_eq!(
+            bus.active_config(),
+            Err(Error::Usb(rusb::Error::NotSupported))
+        );
+        assert_eq!(bus.interfaces(), Err(Error::Usb(rusb::Error::NotSupported)));
+    }
+
+    #[test]
+    fn test_clear_error_state() {
+        let dev = MockConnection::new();
+        dev.schedule_read(CMD_GET_STATUS, I2C_M_RD, 0, &[STATUS_IDLE]);
+        for x in [0u16, 0xaaaa, 0x5555, 0xffff, 0x55aa, 0xaa55, 0x0f0f, 0xf0f0] {
+            dev.schedule_read(CMD_ECHO, x, 0, &x.to_le_bytes());
+        }
+        dev.schedule_read(CMD_GET_STATUS, I2C_M_RD, 0, &[STATUS_IDLE]);
+        dev.schedule_read(CMD_ECHO, 0, 0, &[0xff, 0xff]);
+        let mut bus = I2c::from_connection(dev, Default::default());
+        assert_eq!(bus.clear_error_state(), Ok(RecoveryStep::StatusRead));
+        // the following steps need a USB device
+        assert_eq!(
+            bus.clear_error_state(),
+            Err(Error::Usb(rusb::Error::NotSupported))
+        );
+    }
+
+    #[test]
+    fn test_read_only_recovery() {
+        let mut bus = I2c::from_connection(MockConnection::new(), Default::default());
+        bus.read_only = true;
+        assert_eq!(bus.reset(), Err(Error::Usb(rusb::Error::Access)));
+        assert_eq!(
+            bus.clear_error_state(),
+            Err(Error::Usb(rusb::Error::Access))
+        );
+    }
+
+    #[test]
+    fn test_bus_delay() {
+        let dev = MockConnection::new();
+        dev.fail_next_write(rusb::Error::Pipe);
+        dev.expect_write(CMD_SET_DELAY, 10, 0, &[]);
+        let mut bus = I2c::from_connection(dev, Default::default());
+        assert_eq!(bus.bus_delay(), None);
+        assert!(bus.set_bus_delay(Duration::from_micros(20)).is_err());
+        assert_eq!(bus.bus_delay(), None);
+        bus.set_bus_delay(Duration::from_micros(10)).unwrap();
+        assert_eq!(bus.bus_delay(), Some(Duration::from_micros(10)));
+    }
+
+    #[test]
+    fn test_write_stream() {
+        struct Failing;
+
+        impl Read for Failing {
+            fn read(&mut self, _buf: &mut [u8]) -> std::io::Result<usize> {
+                Err(std::io::Error::other("source failed"))
+            }
+        }
+
+        let dev = MockConnection::new();
+        dev.expect_write(CMD_I2C_IO | CMD_I2C_BEGIN | CMD_I2C_END, 0, 0x50, &[1, 2]);
+        dev.schedule_read(CMD_GET_STATUS, I2C_M_RD, 0, &[STATUS_ADDRESS_ACK]);
+        dev.schedule_read(CMD_GET_STATUS, I2C_M_RD, 0, &[STATUS_ADDRESS_NAK]);
+        let mut bus = I2c::from_connection(dev, Default::default());
+
+        // the source error is passed on unchanged along with the progress so far
+        let err = bus
+            .write_stream(0x50, [1, 2, 3].chain(Failing), 2)
+            .unwrap_err();
+        assert_eq!(err.written, 2);
+        match err.source {
+            WriteStreamFailure::Read(e) => assert_eq!(e.to_string(), "source failed"),
+            e => panic!("unexpected error {e:?}"),
+        }
+
+        let err = bus.write_stream(0x50, &[4u8][..], 2).unwrap_err();
+        assert_eq!(err.written, 0);
+        assert!(matches!(err.source, WriteStreamFailure::Write(Error::Nack)));
+    }
+
+    #[test]
+    fn test_reg_u8() {
+        use i2c::{Address, Smbus};
+
+        let dev = MockConnection::new();
+        dev.schedule_read(CMD_GET_STATUS, I2C_M_RD, 0, &[STATUS_ADDRESS_ACK]);
+        dev.schedule_read(CMD_I2C_IO | CMD_I2C_END, I2C_M_RD, 0x50, &[0xaa]);
+        dev.schedule_read(CMD_GET_STATUS, I2C_M_RD, 0, &[STATUS_IDLE]);
+        dev.expect_write(CMD_I2C_IO | CMD_I2C_BEGIN, 0, 0x50, &[0x10]);
+        dev.expect_write(
+            CMD_I2C_IO | CMD_I2C_BEGIN | CMD_I2C_END,
+            0,
+            0x50,
+            &[0x11, 0x22],
+        );
+        dev.schedule_read(CMD_GET_STATUS, I2C_M_RD, 0, &[STATUS_ADDRESS_ACK]);
+        dev.schedule_read(
+            CMD_I2C_IO | CMD_I2C_BEGIN | CMD_I2C_END,
+            I2C_M_RD,
+            0x50,
+            &[0xbb],
+        );
+        dev.schedule_read(CMD_GET_STATUS, I2C_M_RD, 0, &[STATUS_IDLE]);
+        let mut bus = I2c::from_connection(dev, Default::default());
+        bus.set_slave_address(0x50, false).unwrap();
+        assert_eq!(bus.read_reg_u8(0x10), Ok(0xaa));
+        bus.write_reg_u8(0x11, 0x22).unwrap();
+        // the trait method reads without a register number
+        assert_eq!(bus.smbus_read_byte(), Ok(0xbb));
+    }
+
+    #[test]
+    fn test_read_partial() {
+        use i2c::Address;
+
+        let dev = MockConnection::new();
+        let begin = CMD_I2C_IO | CMD_I2C_BEGIN;
+        let end = CMD_I2C_IO | CMD_I2C_END;
+        // a short first chunk is followed by an empty one ending the transaction
+        dev.schedule_read(begin, I2C_M_RD, 0x50, &[0xaa]);
+        dev.schedule_read(end, I2C_M_RD, 0x50, &[]);
+        dev.schedule_read(CMD_GET_STATUS, I2C_M_RD, 0, &[STATUS_IDLE]);
+        dev.schedule_read(begin, I2C_M_RD, 0x50, &[0xbb]);
+        dev.schedule_read(end, I2C_M_RD, 0x50, &[]);
+        dev.schedule_read(CMD_GET_STATUS, I2C_M_RD, 0, &[STATUS_IDLE]);
+        dev.schedule_read(begin, I2C_M_RD, 0x50, &[0x01, 0x02]);
+        dev.schedule_read(end, I2C_M_RD, 0x50, &[0x03, 0x04]);
+        dev.schedule_read(CMD_GET_STATUS, I2C_M_RD, 0, &[STATUS_IDLE]);
+        let mut bus = I2c::from_connection(dev, Default::default());
+        bus.set_max_transfer_len(2).unwrap();
+        let mut buf = [0u8; 4];
+        assert_eq!(bus.read_partial(0x50, &mut buf), Ok(1));
+        assert_eq!(buf[0], 0xaa);
+
+        // std::io reads fail instead so that read_exact doesn't append the data of another read
+        bus.set_slave_address(0x50, false).unwrap();
+        assert!(bus.read_exact(&mut buf).is_err());
+        assert_eq!(bus.read_partial(0x50, &mut buf), Ok(4));
+        assert_eq!(buf, [0x01, 0x02, 0x03, 0x04]);
+    }
+
+    #[test]
+    fn test_supports_flags() {
+        use i2c::{ReadFlags, WriteFlags};
+
+        let plain = FunctionFlags(I2C_FUNC_I2C).transfer_flags();
+        let bus = I2c::from_connection(MockConnection::new(), plain);
+        assert!(bus.supports_flags(ReadFlags::empty(), WriteFlags::empty()));
+        assert!(!bus.supports_flags(ReadFlags::NO_START, WriteFlags::empty()));
+        assert!(!bus.supports_flags(ReadFlags::empty(), WriteFlags::IGNORE_NACK));
+
+        let mangling = FunctionFlags(I2C_FUNC_I2C | I2C_FUNC_PROTOCOL_MANGLING).transfer_flags();
+        let bus = I2c::from_connection(MockConnection::new(), mangling);
+        assert!(bus.supports_flags(ReadFlags::NO_START, WriteFlags::empty()));
+        assert!(bus.supports_flags(
+            ReadFlags::NACK | ReadFlags::REVERSE_RW,
+            WriteFlags::IGNORE_NACK | WriteFlags::NO_START
+        ));
     }
 }
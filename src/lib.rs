@@ -1,35 +1,110 @@
+#[cfg(feature = "async")]
+mod async_i2c;
+mod buffered;
+mod builder;
+pub mod checksum;
 mod connection;
+#[cfg(feature = "dry-run")]
+mod dry_run;
+mod eeprom;
+#[cfg(feature = "embedded-hal")]
+mod embedded_hal_impl;
 mod error;
+#[cfg(feature = "hotplug")]
+mod hotplug;
 mod i2c_impl;
+pub mod messages;
+mod multi_bus;
+mod owned;
 mod protocol;
+mod sequence;
+mod shared;
+mod trace;
 
 #[cfg(all(test, feature = "hw-tests"))]
 mod hw_tests;
 
-pub(crate) use connection::Connection;
+pub use connection::Connection;
 
+#[cfg(feature = "async")]
+pub use async_i2c::AsyncI2c;
+pub use buffered::BufferedI2c;
+pub use builder::I2cBuilder;
+#[cfg(feature = "dry-run")]
+pub use dry_run::DryRun;
+pub use eeprom::{Eeprom, EepromAddressing};
 pub use error::*;
+#[cfg(feature = "hotplug")]
+pub use hotplug::{watch_devices, DeviceWatcher, HotplugEvent};
 pub use i2c_impl::*;
+pub use multi_bus::MultiBus;
+pub use owned::OwnedMessage;
+pub use protocol::{Firmware, FunctionFlags, ReservedAddressPolicy, ScanResult};
 pub use rusb;
+pub use sequence::RawSequence;
+pub use shared::SharedI2c;
+pub use trace::{CommandTiming, TimingStats, TraceDirection, TraceEvent, TraceHook};
 
 use rusb::{Device, GlobalContext, UsbContext};
+use std::sync::Mutex;
+
+// additional VID+PID combinations added with `register_vendor_product()`
+static REGISTERED_VENDOR_PRODUCT_IDS: Mutex<Vec<(u16, u16)>> = Mutex::new(Vec::new());
+
+/// Adds a USB vendor and product ID combination to the ones recognized by
+/// [`is_supported_device`] and [`devices`]. Useful for compatible adapters with IDs unknown to
+/// this library.
+pub fn register_vendor_product(vid: u16, pid: u16) {
+    let mut ids = REGISTERED_VENDOR_PRODUCT_IDS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner());
+    if !ids.contains(&(vid, pid)) {
+        ids.push((vid, pid));
+    }
+}
+
+fn is_known_vendor_product(vid: u16, pid: u16) -> bool {
+    if protocol::KNOWN_VENDOR_PRODUCT_IDS.contains(&(vid, pid)) {
+        return true;
+    }
+    REGISTERED_VENDOR_PRODUCT_IDS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .contains(&(vid, pid))
+}
 
 pub fn is_supported_device<T: UsbContext>(dev: &Device<T>) -> bool {
     let desc = match dev.device_descriptor() {
         Err(_) => return false,
         Ok(x) => x,
     };
-    for (vid, pid) in protocol::KNOWN_VENDOR_PRODUCT_IDS {
-        if desc.vendor_id() == vid && desc.product_id() == pid {
-            return true;
-        }
-    }
-    false
+    is_known_vendor_product(desc.vendor_id(), desc.product_id())
 }
 
+/// Like [`try_devices`] but returns an empty list if enumerating the USB devices failed.
 pub fn devices() -> Vec<Device<GlobalContext>> {
-    match rusb::devices() {
-        Err(_) => vec![],
-        Ok(devs) => devs.iter().filter(is_supported_device).collect(),
+    try_devices().unwrap_or_default()
+}
+
+/// Returns the connected supported devices. Errors from enumerating the USB devices, e.g. missing
+/// permissions, are returned rather than treated as no devices being present.
+pub fn try_devices() -> Result<Vec<Device<GlobalContext>>> {
+    Ok(rusb::devices()?
+        .iter()
+        .filter(is_supported_device)
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_register_vendor_product() {
+        assert!(is_known_vendor_product(0x0403, 0xc631));
+        assert!(!is_known_vendor_product(0x1234, 0x5678));
+        register_vendor_product(0x1234, 0x5678);
+        assert!(is_known_vendor_product(0x1234, 0x5678));
+        assert!(!is_known_vendor_product(0x1234, 0x5679));
     }
 }
@@ -0,0 +1,31 @@
+//! Shorthands for constructing [`i2c::Message`]s, e.g. for [`i2c::BulkTransfer::i2c_transfer`].
+
+use i2c::{Message, ReadFlags, WriteFlags};
+
+/// Reads `data.len()` bytes from the peripheral at `address`.
+pub fn read(address: u16, data: &mut [u8]) -> Message<'_> {
+    read_flags(address, data, ReadFlags::empty())
+}
+
+/// Writes `data` to the peripheral at `address`.
+pub fn write(address: u16, data: &[u8]) -> Message<'_> {
+    write_flags(address, data, WriteFlags::empty())
+}
+
+/// Like [`read`] but with custom flags.
+pub fn read_flags(address: u16, data: &mut [u8], flags: ReadFlags) -> Message<'_> {
+    Message::Read {
+        address,
+        data,
+        flags,
+    }
+}
+
+/// Like [`write`] but with custom flags.
+pub fn write_flags(address: u16, data: &[u8], flags: WriteFlags) -> Message<'_> {
+    Message::Write {
+        address,
+        data,
+        flags,
+    }
+}
@@ -0,0 +1,143 @@
+use i2c::{BulkTransfer, Message};
+use rusb::UsbContext;
+use std::collections::BTreeMap;
+
+use crate::{messages, I2c, Result};
+
+/// Several adapters, each driving its own bus segment, used as one bus. Transfers are routed to
+/// an adapter by the peripheral address according to a map set up with [`MultiBus::route`].
+///
+/// Addresses that aren't mapped and transfers addressing peripherals behind different adapters
+/// fail with `rusb::Error::InvalidParam`.
+pub struct MultiBus<T: UsbContext> {
+    buses: Vec<I2c<T>>,
+    routes: BTreeMap<u16, usize>,
+}
+
+impl<T: UsbContext> Default for MultiBus<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: UsbContext> MultiBus<T> {
+    pub fn new() -> Self {
+        Self {
+            buses: Vec::new(),
+            routes: BTreeMap::new(),
+        }
+    }
+
+    /// Adds an adapter and returns its index for use with [`MultiBus::route`].
+    pub fn add_bus(&mut self, i2c: I2c<T>) -> usize {
+        self.buses.push(i2c);
+        self.buses.len() - 1
+    }
+
+    /// Routes transfers to the peripheral at `addr` to the adapter with index `bus`, replacing
+    /// any previous route for that address.
+    pub fn route(&mut self, addr: u16, bus: usize) -> Result<()> {
+        if bus >= self.buses.len() {
+            return Err(rusb::Error::InvalidParam.into());
+        }
+        self.routes.insert(addr, bus);
+        Ok(())
+    }
+
+    /// Returns the adapter with index `bus`, e.g. for changing its settings.
+    pub fn bus(&mut self, bus: usize) -> Option<&mut I2c<T>> {
+        self.buses.get_mut(bus)
+    }
+
+    /// Returns the adapter that transfers to `addr` are routed to.
+    pub fn bus_for(&mut self, addr: u16) -> Result<&mut I2c<T>> {
+        match self.routes.get(&addr) {
+            Some(&bus) => Ok(&mut self.buses[bus]),
+            None => Err(rusb::Error::InvalidParam.into()),
+        }
+    }
+
+    /// Performs the same transfer as [`i2c::BulkTransfer::i2c_transfer`] on the adapter the
+    /// addresses of `messages` are routed to. All of them must be routed to the same adapter.
+    pub fn transfer(&mut self, messages: &mut [Message]) -> Result<()> {
+        let mut bus = None;
+        for message in messages.iter() {
+            let (Message::Read { address, .. } | Message::Write { address, .. }) = message;
+            let message_bus = self.routes.get(address).copied();
+            if message_bus.is_none() || (bus.is_some() && bus != message_bus) {
+                return Err(rusb::Error::InvalidParam.into());
+            }
+            bus = message_bus;
+        }
+        match bus {
+            Some(bus) => self.buses[bus].i2c_transfer(messages),
+            None => Ok(()), // no messages
+        }
+    }
+
+    /// Reads `buf.len()` bytes from the peripheral at `addr`.
+    pub fn read(&mut self, addr: u16, buf: &mut [u8]) -> Result<()> {
+        self.transfer(&mut [messages::read(addr, buf)])
+    }
+
+    /// Writes `data` to the peripheral at `addr`.
+    pub fn write(&mut self, addr: u16, data: &[u8]) -> Result<()> {
+        self.transfer(&mut [messages::write(addr, data)])
+    }
+
+    /// See [`I2c::write_read`].
+    pub fn write_read(&mut self, addr: u16, write: &[u8], read: &mut [u8]) -> Result<()> {
+        self.bus_for(addr)?.write_read(addr, write, read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::mock::MockConnection;
+    use crate::protocol::constants::*;
+    use crate::Error;
+
+    fn mock_bus(data: u8) -> I2c<rusb::GlobalContext> {
+        let dev = MockConnection::new();
+        dev.schedule_read(
+            CMD_I2C_IO | CMD_I2C_BEGIN | CMD_I2C_END,
+            I2C_M_RD,
+            0x50,
+            &[data],
+        );
+        dev.schedule_read(CMD_GET_STATUS, I2C_M_RD, 0, &[STATUS_IDLE]);
+        I2c::from_connection(dev, Default::default())
+    }
+
+    #[test]
+    fn test_routing() {
+        let mut multi = MultiBus::new();
+        let first = multi.add_bus(mock_bus(0xaa));
+        let second = multi.add_bus(mock_bus(0xbb));
+        assert_eq!(
+            multi.route(0x50, 2),
+            Err(Error::Usb(rusb::Error::InvalidParam))
+        );
+        multi.route(0x50, second).unwrap();
+        multi.route(0x51, first).unwrap();
+
+        let mut buf = [0u8; 1];
+        multi.read(0x50, &mut buf).unwrap();
+        assert_eq!(buf, [0xbb]);
+        assert_eq!(
+            multi.read(0x52, &mut buf),
+            Err(Error::Usb(rusb::Error::InvalidParam))
+        );
+
+        // messages of one transfer can't be split between adapters
+        let mut buf2 = [0u8; 1];
+        assert_eq!(
+            multi.transfer(&mut [
+                messages::read(0x50, &mut buf),
+                messages::read(0x51, &mut buf2)
+            ]),
+            Err(Error::Usb(rusb::Error::InvalidParam))
+        );
+    }
+}
@@ -0,0 +1,98 @@
+use i2c::{Message, ReadFlags, WriteFlags};
+
+/// Counterpart of [`i2c::Message`] owning its data so that transfer sequences can be stored and
+/// replayed, e.g. with [`crate::I2c::transfer_owned`]. For reads, `data` determines the length and
+/// receives the data read.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OwnedMessage {
+    Read {
+        address: u16,
+        data: Vec<u8>,
+        flags: ReadFlags,
+    },
+    Write {
+        address: u16,
+        data: Vec<u8>,
+        flags: WriteFlags,
+    },
+}
+
+impl OwnedMessage {
+    /// Borrows the message as an [`i2c::Message`].
+    pub fn to_message(&mut self) -> Message<'_> {
+        match self {
+            OwnedMessage::Read {
+                address,
+                data,
+                flags,
+            } => Message::Read {
+                address: *address,
+                data,
+                flags: *flags,
+            },
+            OwnedMessage::Write {
+                address,
+                data,
+                flags,
+            } => Message::Write {
+                address: *address,
+                data,
+                flags: *flags,
+            },
+        }
+    }
+}
+
+/// Copies the data of the message, including the current content of read buffers.
+impl From<&Message<'_>> for OwnedMessage {
+    fn from(message: &Message<'_>) -> Self {
+        match message {
+            Message::Read {
+                address,
+                data,
+                flags,
+            } => OwnedMessage::Read {
+                address: *address,
+                data: data.to_vec(),
+                flags: *flags,
+            },
+            Message::Write {
+                address,
+                data,
+                flags,
+            } => OwnedMessage::Write {
+                address: *address,
+                data: data.to_vec(),
+                flags: *flags,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let mut buf = [1u8, 2];
+        let message = Message::Read {
+            address: 0x50,
+            data: &mut buf,
+            flags: ReadFlags::empty(),
+        };
+        let mut owned = OwnedMessage::from(&message);
+        if let Message::Read { address, data, .. } = owned.to_message() {
+            assert_eq!(address, 0x50);
+            data[0] = 3;
+        }
+        assert_eq!(
+            owned,
+            OwnedMessage::Read {
+                address: 0x50,
+                data: vec![3, 2],
+                flags: ReadFlags::empty()
+            }
+        );
+    }
+}
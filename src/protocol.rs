@@ -1,16 +1,49 @@
 use i2c::{Message, ReadFlags, WriteFlags};
-use std::time::Duration;
+use std::ops::{Range, RangeInclusive};
+use std::time::{Duration, Instant};
 
-use crate::{Connection, Error, Result};
+use crate::{Connection, Error, Result, TransferError};
 
 // i2c-tiny-usb and compatible devices can use multiple USB VID+PID combinations
-pub(crate) const KNOWN_VENDOR_PRODUCT_IDS: [(u16, u16); 2] = [
-    (0x0403, 0xc631), // FTDI
-    (0x1c40, 0x0534), // EZPrototypes
-];
+const FTDI_VENDOR_PRODUCT_ID: (u16, u16) = (0x0403, 0xc631);
+const EZPROTOTYPES_VENDOR_PRODUCT_ID: (u16, u16) = (0x1c40, 0x0534);
+pub(crate) const KNOWN_VENDOR_PRODUCT_IDS: [(u16, u16); 2] =
+    [FTDI_VENDOR_PRODUCT_ID, EZPROTOTYPES_VENDOR_PRODUCT_ID];
+
+/// Adapter type inferred from the USB vendor and product ID, see [`crate::I2c::firmware`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Firmware {
+    /// The original i2c-tiny-usb firmware using an FTDI product ID.
+    Ftdi,
+    /// Firmware using the EZPrototypes product ID, e.g. i2c-star.
+    EzPrototypes,
+    /// IDs added with [`crate::register_vendor_product`].
+    Unknown,
+}
+
+impl Firmware {
+    pub fn from_vendor_product(vid: u16, pid: u16) -> Self {
+        match (vid, pid) {
+            FTDI_VENDOR_PRODUCT_ID => Firmware::Ftdi,
+            EZPROTOTYPES_VENDOR_PRODUCT_ID => Firmware::EzPrototypes,
+            _ => Firmware::Unknown,
+        }
+    }
+
+    /// Maximum read length known to work reliably, used as the initial value of
+    /// [`crate::I2c::set_max_transfer_len`]. Single control transfers of 102 bytes or more break
+    /// i2c-star, see `hw_tests.rs`, so unknown firmware gets the same conservative limit. The
+    /// original firmware handles long reads since the Linux driver never splits them.
+    pub fn default_max_read_len(&self) -> usize {
+        match self {
+            Firmware::Ftdi => FTDI_MAX_READ_LEN,
+            Firmware::EzPrototypes | Firmware::Unknown => DEFAULT_MAX_READ_LEN,
+        }
+    }
+}
 
 #[allow(dead_code)]
-mod constants {
+pub(crate) mod constants {
     pub const CMD_ECHO: u8 = 0;
     pub const CMD_GET_FUNC: u8 = 1;
     pub const CMD_SET_DELAY: u8 = 2;
@@ -21,14 +54,37 @@ mod constants {
     pub const CMD_I2C_BEGIN: u8 = 1;
     pub const CMD_I2C_END: u8 = 2;
 
-    // possible values for the CMD_GET_STATUS response
+    // possible values for the CMD_GET_STATUS response; the reference firmware defines no others,
+    // arbitration loss in particular is not detected, and any other value is reported as
+    // `Error::BusError`
     pub const STATUS_IDLE: u8 = 0;
     pub const STATUS_ADDRESS_ACK: u8 = 1;
     pub const STATUS_ADDRESS_NAK: u8 = 2;
 
-    // possible values for the CMD_GET_FUNC response
+    // bits of the CMD_GET_FUNC response (same as in linux/i2c.h)
     pub const I2C_FUNC_I2C: u32 = 0x00000001;
+    pub const I2C_FUNC_10BIT_ADDR: u32 = 0x00000002;
     pub const I2C_FUNC_PROTOCOL_MANGLING: u32 = 0x00000004;
+    pub const I2C_FUNC_SMBUS_PEC: u32 = 0x00000008;
+    pub const I2C_FUNC_NOSTART: u32 = 0x00000010;
+    pub const I2C_FUNC_SLAVE: u32 = 0x00000020;
+    pub const I2C_FUNC_SMBUS_BLOCK_PROC_CALL: u32 = 0x00008000;
+    pub const I2C_FUNC_SMBUS_QUICK: u32 = 0x00010000;
+    pub const I2C_FUNC_SMBUS_READ_BYTE: u32 = 0x00020000;
+    pub const I2C_FUNC_SMBUS_WRITE_BYTE: u32 = 0x00040000;
+    pub const I2C_FUNC_SMBUS_READ_BYTE_DATA: u32 = 0x00080000;
+    pub const I2C_FUNC_SMBUS_WRITE_BYTE_DATA: u32 = 0x00100000;
+    pub const I2C_FUNC_SMBUS_READ_WORD_DATA: u32 = 0x00200000;
+    pub const I2C_FUNC_SMBUS_WRITE_WORD_DATA: u32 = 0x00400000;
+    pub const I2C_FUNC_SMBUS_PROC_CALL: u32 = 0x00800000;
+    pub const I2C_FUNC_SMBUS_READ_BLOCK_DATA: u32 = 0x01000000;
+    pub const I2C_FUNC_SMBUS_WRITE_BLOCK_DATA: u32 = 0x02000000;
+    pub const I2C_FUNC_SMBUS_READ_I2C_BLOCK: u32 = 0x04000000;
+    pub const I2C_FUNC_SMBUS_WRITE_I2C_BLOCK: u32 = 0x08000000;
+    pub const I2C_FUNC_SMBUS_HOST_NOTIFY: u32 = 0x10000000;
+
+    // bits not defined by linux/i2c.h, no real firmware sets them
+    pub const I2C_FUNC_RESERVED: u32 = 0xe0007fc0;
 
     // per-message flags
     pub const I2C_M_RD: u16 = 0x0001;
@@ -42,13 +98,179 @@ use constants::*;
 // control transfer parameters
 pub const TIMEOUT: Duration = Duration::from_secs(1);
 
+/// Largest valid 7-bit I2C address.
+pub(crate) const MAX_ADDRESS: u16 = 0x7f;
+
+/// Address of the general call, a broadcast to all peripherals that support it.
+pub(crate) const GENERAL_CALL_ADDRESS: u16 = 0x00;
+
+// Bigger reads are known to fail or lock up some devices so we split them into smaller transfers.
+pub(crate) const DEFAULT_MAX_READ_LEN: usize = 64;
+// The original firmware has no such problem but very long transfers would need longer timeouts.
+const FTDI_MAX_READ_LEN: usize = 256;
+
+/// Settings affecting how [`transfer`] maps I2C messages to USB control transfers.
+#[derive(Debug, Clone)]
+pub(crate) struct TransferOptions {
+    /// Maximum number of bytes read in a single control transfer; must not be zero.
+    pub max_read_len: usize,
+    /// Number of times a transfer is repeated after a NACK before giving up.
+    pub retries: u8,
+    /// Time to wait before each retry.
+    pub retry_backoff: Duration,
+    /// Timeout of each control transfer performing I2C I/O.
+    pub timeout: Duration,
+    pub reserved_address_policy: ReservedAddressPolicy,
+    /// If set, the control transfers of a transfer use the time left until the deadline as their
+    /// timeout instead of `timeout`.
+    pub deadline: Option<Instant>,
+    /// Merge adjacent messages with the same address, direction and flags into one, see
+    /// [`merge_groups`].
+    pub merge_adjacent: bool,
+    /// Read the status after each message to detect NACKs. If disabled, only a stalled control
+    /// transfer is reported as a NACK.
+    pub check_status: bool,
+}
+
+impl Default for TransferOptions {
+    fn default() -> Self {
+        Self {
+            max_read_len: DEFAULT_MAX_READ_LEN,
+            retries: 0,
+            retry_backoff: Duration::ZERO,
+            timeout: TIMEOUT,
+            reserved_address_policy: ReservedAddressPolicy::Allow,
+            deadline: None,
+            merge_adjacent: false,
+            check_status: true,
+        }
+    }
+}
+
+/// Whether transfers to the reserved addresses 0x00 to 0x02 (general call, CBUS and other bus
+/// formats) and 0x78 to 0x7f (10-bit addressing and future use) are allowed. These are the
+/// addresses skipped by `i2cdetect`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ReservedAddressPolicy {
+    #[default]
+    Allow,
+    /// Fail transfers to reserved addresses with `rusb::Error::InvalidParam`.
+    Reject,
+}
+
+impl ReservedAddressPolicy {
+    fn allows(&self, address: u16) -> bool {
+        *self == ReservedAddressPolicy::Allow || (0x03..=0x77).contains(&address)
+    }
+}
+
+/// Functionality bitmask reported by the device. The bits have the same meaning as the `I2C_FUNC_*`
+/// constants used by the Linux kernel.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FunctionFlags(pub u32);
+
+impl FunctionFlags {
+    #[inline]
+    fn has(&self, bit: u32) -> bool {
+        self.0 & bit != 0
+    }
+
+    /// Plain I2C transfers (required by this library).
+    pub fn supports_i2c(&self) -> bool {
+        self.has(I2C_FUNC_I2C)
+    }
+
+    pub fn supports_10bit_addr(&self) -> bool {
+        self.has(I2C_FUNC_10BIT_ADDR)
+    }
+
+    /// Non-standard transfers, i.e. the `NACK`, `IGNORE_NACK`, `REVERSE_RW` and `NO_START` flags.
+    pub fn supports_protocol_mangling(&self) -> bool {
+        self.has(I2C_FUNC_PROTOCOL_MANGLING)
+    }
+
+    pub fn supports_smbus_pec(&self) -> bool {
+        self.has(I2C_FUNC_SMBUS_PEC)
+    }
+
+    pub fn supports_nostart(&self) -> bool {
+        self.has(I2C_FUNC_NOSTART)
+    }
+
+    pub fn supports_smbus_block_proc_call(&self) -> bool {
+        self.has(I2C_FUNC_SMBUS_BLOCK_PROC_CALL)
+    }
+
+    pub fn supports_smbus_quick(&self) -> bool {
+        self.has(I2C_FUNC_SMBUS_QUICK)
+    }
+
+    pub fn supports_smbus_read_byte(&self) -> bool {
+        self.has(I2C_FUNC_SMBUS_READ_BYTE)
+    }
+
+    pub fn supports_smbus_write_byte(&self) -> bool {
+        self.has(I2C_FUNC_SMBUS_WRITE_BYTE)
+    }
+
+    pub fn supports_smbus_read_byte_data(&self) -> bool {
+        self.has(I2C_FUNC_SMBUS_READ_BYTE_DATA)
+    }
+
+    pub fn supports_smbus_write_byte_data(&self) -> bool {
+        self.has(I2C_FUNC_SMBUS_WRITE_BYTE_DATA)
+    }
+
+    pub fn supports_smbus_read_word_data(&self) -> bool {
+        self.has(I2C_FUNC_SMBUS_READ_WORD_DATA)
+    }
+
+    pub fn supports_smbus_write_word_data(&self) -> bool {
+        self.has(I2C_FUNC_SMBUS_WRITE_WORD_DATA)
+    }
+
+    pub fn supports_smbus_proc_call(&self) -> bool {
+        self.has(I2C_FUNC_SMBUS_PROC_CALL)
+    }
+
+    pub fn supports_smbus_read_block_data(&self) -> bool {
+        self.has(I2C_FUNC_SMBUS_READ_BLOCK_DATA)
+    }
+
+    pub fn supports_smbus_write_block_data(&self) -> bool {
+        self.has(I2C_FUNC_SMBUS_WRITE_BLOCK_DATA)
+    }
+
+    pub fn supports_smbus_read_i2c_block(&self) -> bool {
+        self.has(I2C_FUNC_SMBUS_READ_I2C_BLOCK)
+    }
+
+    pub fn supports_smbus_write_i2c_block(&self) -> bool {
+        self.has(I2C_FUNC_SMBUS_WRITE_I2C_BLOCK)
+    }
+
+    /// Read and write flags that can be used in [`i2c::BulkTransfer`] messages.
+    pub(crate) fn transfer_flags(&self) -> (ReadFlags, WriteFlags) {
+        // non-standard I2C transfers are only possible if the device supports protocol mangling
+        if self.supports_protocol_mangling() {
+            (
+                ReadFlags::NACK | ReadFlags::REVERSE_RW | ReadFlags::NO_START,
+                WriteFlags::IGNORE_NACK | WriteFlags::REVERSE_RW | WriteFlags::NO_START,
+            )
+        } else {
+            Default::default()
+        }
+    }
+}
+
 fn dev_read(
     dev: &impl Connection,
     command: u8,
     flags: ReadFlags,
     arg: u16,
     data: &mut [u8],
-) -> Result<()> {
+    timeout: Duration,
+) -> Result<usize> {
     let mut flag_bits = I2C_M_RD; // needs to be set for all I2C reads
     if flags.contains(ReadFlags::NACK) {
         flag_bits |= I2C_M_NO_RD_ACK;
@@ -64,12 +286,13 @@ fn dev_read(
         LIBUSB_REQUEST_TYPE_VENDOR | LIBUSB_RECIPIENT_INTERFACE | LIBUSB_ENDPOINT_IN
     };
 
-    let n_read = dev.read_control(req_type, command, flag_bits, arg, data, TIMEOUT)?;
-    if n_read != data.len() {
-        Err(rusb::Error::Io.into())
-    } else {
-        Ok(())
-    }
+    let result = dev.read_control(req_type, command, flag_bits, arg, data, timeout);
+    #[cfg(feature = "log")]
+    log::debug!(
+        "read: command={command:#04x} flags={flag_bits:#06x} address={arg:#06x} len={} -> {result:?}",
+        data.len()
+    );
+    Ok(result?)
 }
 
 fn dev_write(
@@ -78,7 +301,8 @@ fn dev_write(
     flags: WriteFlags,
     arg: u16,
     data: &[u8],
-) -> Result<()> {
+    timeout: Duration,
+) -> Result<usize> {
     let mut flag_bits = 0;
     if flags.contains(WriteFlags::IGNORE_NACK) {
         flag_bits |= I2C_M_IGNORE_NAK;
@@ -94,221 +318,1946 @@ fn dev_write(
         LIBUSB_REQUEST_TYPE_VENDOR | LIBUSB_RECIPIENT_INTERFACE | LIBUSB_ENDPOINT_OUT
     };
 
-    let n_written = dev.write_control(req_type, command, flag_bits, arg, data, TIMEOUT)?;
-    if n_written != data.len() {
-        Err(rusb::Error::Io.into())
+    let result = dev.write_control(req_type, command, flag_bits, arg, data, timeout);
+    #[cfg(feature = "log")]
+    log::debug!(
+        "write: command={command:#04x} flags={flag_bits:#06x} address={arg:#06x} len={} -> {result:?}",
+        data.len()
+    );
+    Ok(result?)
+}
+
+/// Returns `Error::ShortTransfer` if fewer than `requested` bytes were transferred. More data than
+/// requested is reported as `rusb::Error::Overflow`, the same error libusb returns when the device
+/// sends more bytes than fit into the buffer, so that over-reads can be told apart from short ones.
+fn check_len(requested: usize, transferred: usize) -> Result<()> {
+    if transferred > requested {
+        Err(rusb::Error::Overflow.into())
+    } else if transferred < requested {
+        Err(Error::ShortTransfer {
+            requested,
+            transferred,
+        })
     } else {
         Ok(())
     }
 }
 
-pub(crate) fn transfer(dev: &impl Connection, messages: &mut [Message]) -> Result<()> {
-    if messages.is_empty() {
-        return Ok(());
+/// Splits `len` bytes into ranges of at most `max_len` bytes. Always yields at least one range so
+/// that zero-length messages are still sent.
+fn chunk_ranges(len: usize, max_len: usize) -> impl ExactSizeIterator<Item = Range<usize>> {
+    let n_chunks = len.div_ceil(max_len).max(1);
+    (0..n_chunks).map(move |i| i * max_len..((i + 1) * max_len).min(len))
+}
+
+/// Returns the `CMD_I2C_IO` variant for a message. The firmware issues a start condition for
+/// messages with `CMD_I2C_BEGIN`, a repeated start for all others and a stop condition after
+/// messages with `CMD_I2C_END`.
+fn io_command(begin: bool, end: bool) -> u8 {
+    let mut cmd = CMD_I2C_IO;
+    if begin {
+        cmd |= CMD_I2C_BEGIN;
     }
-    let i_message_end = messages.len() - 1; // no underflow because of is_empty() check above
-    for (i_message, message) in messages.iter_mut().enumerate() {
-        let mut cmd = CMD_I2C_IO;
-        if i_message == 0 {
-            cmd |= CMD_I2C_BEGIN;
+    if end {
+        cmd |= CMD_I2C_END;
+    }
+    cmd
+}
+
+/// Performs the whole sequence of I2C messages, repeating it up to `options.retries` times if it's
+/// not acknowledged. Other errors are returned immediately. Messages with invalid addresses or a
+/// first message with the `NO_START` flag fail with `rusb::Error::InvalidParam` before anything is
+/// sent.
+pub(crate) fn transfer(
+    dev: &impl Connection,
+    options: &TransferOptions,
+    messages: &mut [Message],
+) -> Result<()> {
+    transfer_detailed(dev, options, messages).map_err(Error::from)
+}
+
+/// Same as [`transfer`] but also reports the index of the message that failed.
+pub(crate) fn transfer_detailed(
+    dev: &impl Connection,
+    options: &TransferOptions,
+    messages: &mut [Message],
+) -> std::result::Result<(), TransferError> {
+    transfer_with_timeouts(dev, options, messages, &[])
+}
+
+/// Same as [`transfer_detailed`] but the USB timeout of each message can be overridden by the
+/// entry at the same index of `timeouts`. Messages with a `None` entry or past the end of
+/// `timeouts` use `options.timeout`.
+pub(crate) fn transfer_with_timeouts(
+    dev: &impl Connection,
+    options: &TransferOptions,
+    messages: &mut [Message],
+    timeouts: &[Option<Duration>],
+) -> std::result::Result<(), TransferError> {
+    // the firmware only supports 7-bit addresses and would silently truncate larger ones
+    if let Some(failed_index) = messages.iter().position(|message| {
+        let (Message::Read { address, .. } | Message::Write { address, .. }) = message;
+        *address > MAX_ADDRESS || !options.reserved_address_policy.allows(*address)
+    }) {
+        return Err(TransferError {
+            failed_index,
+            source: rusb::Error::InvalidParam.into(),
+        });
+    }
+
+    // NO_START continues the previous message without a repeated start, the first message of a
+    // transfer has nothing to continue
+    if let Some(Message::Read { flags, .. }) = messages.first() {
+        if flags.contains(ReadFlags::NO_START) {
+            return Err(TransferError {
+                failed_index: 0,
+                source: rusb::Error::InvalidParam.into(),
+            });
+        }
+    }
+    if let Some(Message::Write { flags, .. }) = messages.first() {
+        if flags.contains(WriteFlags::NO_START) {
+            return Err(TransferError {
+                failed_index: 0,
+                source: rusb::Error::InvalidParam.into(),
+            });
+        }
+    }
+
+    if options.merge_adjacent {
+        transfer_merged(dev, options, messages, timeouts)
+    } else {
+        transfer_retrying(dev, options, messages, timeouts)
+    }
+}
+
+fn transfer_retrying(
+    dev: &impl Connection,
+    options: &TransferOptions,
+    messages: &mut [Message],
+    timeouts: &[Option<Duration>],
+) -> std::result::Result<(), TransferError> {
+    let mut retries_left = options.retries;
+    loop {
+        match transfer_once(dev, options, messages, timeouts) {
+            Err(TransferError {
+                source: Error::Nack | Error::DataNack,
+                ..
+            }) if retries_left > 0 => {
+                retries_left -= 1;
+                std::thread::sleep(options.retry_backoff);
+            }
+            result => return result,
         }
-        if i_message == i_message_end {
-            cmd |= CMD_I2C_END;
+    }
+}
+
+/// Splits `messages` into ranges of adjacent messages that can be sent as one: same address,
+/// direction and flags. The peripheral sees one longer message instead of several separated by
+/// repeated starts, which is only equivalent if it doesn't care about the repeated start.
+pub(crate) fn merge_groups(messages: &[Message]) -> Vec<Range<usize>> {
+    let mut groups: Vec<Range<usize>> = Vec::new();
+    for (i, message) in messages.iter().enumerate() {
+        match groups.last_mut() {
+            Some(group) if mergeable(&messages[group.start], message) => group.end = i + 1,
+            _ => groups.push(i..i + 1),
         }
+    }
+    groups
+}
 
-        let op_result = match message {
+fn mergeable(a: &Message, b: &Message) -> bool {
+    match (a, b) {
+        (
+            Message::Read {
+                address: a1,
+                flags: f1,
+                ..
+            },
             Message::Read {
+                address: a2,
+                flags: f2,
+                ..
+            },
+        ) => a1 == a2 && f1 == f2,
+        (
+            Message::Write {
+                address: a1,
+                flags: f1,
+                ..
+            },
+            Message::Write {
+                address: a2,
+                flags: f2,
+                ..
+            },
+        ) => a1 == a2 && f1 == f2,
+        _ => false,
+    }
+}
+
+/// Same as [`transfer_retrying`] but merges adjacent messages according to [`merge_groups`]. Each
+/// merged message uses the timeout of its first message and errors are reported at the index of
+/// the first message of the group.
+fn transfer_merged(
+    dev: &impl Connection,
+    options: &TransferOptions,
+    messages: &mut [Message],
+    timeouts: &[Option<Duration>],
+) -> std::result::Result<(), TransferError> {
+    let groups = merge_groups(messages);
+    if groups.len() == messages.len() {
+        return transfer_retrying(dev, options, messages, timeouts);
+    }
+
+    let mut buffers: Vec<Vec<u8>> = groups
+        .iter()
+        .map(|group| {
+            let mut buffer = Vec::new();
+            for message in &messages[group.clone()] {
+                match message {
+                    Message::Read { data, .. } => buffer.resize(buffer.len() + data.len(), 0),
+                    Message::Write { data, .. } => buffer.extend_from_slice(data),
+                }
+            }
+            buffer
+        })
+        .collect();
+    let mut merged: Vec<Message> = groups
+        .iter()
+        .zip(buffers.iter_mut())
+        .map(|(group, buffer)| match messages[group.start] {
+            Message::Read { address, flags, .. } => Message::Read {
                 address,
-                data,
+                data: buffer,
                 flags,
-            } => dev_read(dev, cmd, *flags, *address, data),
-            Message::Write {
+            },
+            Message::Write { address, flags, .. } => Message::Write {
                 address,
-                data,
+                data: buffer,
                 flags,
-            } => dev_write(dev, cmd, *flags, *address, data),
-        };
+            },
+        })
+        .collect();
+    let merged_timeouts: Vec<Option<Duration>> = groups
+        .iter()
+        .map(|group| timeouts.get(group.start).copied().flatten())
+        .collect();
 
-        // Typically when there is no acknowledgement, the `op_result` will be a failure because the
-        // corresponding USB control transfer is not acknowledged either. We check the status
-        // regardless to distinguish this from other errors and in case there are devices that
-        // behave differently.
-        let mut status: [u8; 1] = [0x0];
-        dev_read(dev, CMD_GET_STATUS, ReadFlags::empty(), 0, &mut status)?;
-        if status[0] == STATUS_ADDRESS_NAK {
-            return Err(Error::Nack);
-        }
+    transfer_retrying(dev, options, &mut merged, &merged_timeouts).map_err(|e| TransferError {
+        failed_index: groups[e.failed_index].start,
+        source: e.source,
+    })?;
+    drop(merged);
 
-        // we still want to return an error if there's no NACK but the main operation failed
-        op_result?;
+    // copy the data read by the merged messages back to the original ones
+    for (group, buffer) in groups.iter().zip(&buffers) {
+        let mut offset = 0;
+        for message in &mut messages[group.clone()] {
+            if let Message::Read { data, .. } = message {
+                data.copy_from_slice(&buffer[offset..offset + data.len()]);
+                offset += data.len();
+            }
+        }
     }
-
     Ok(())
 }
 
-/// Issues some test commands and probes the functionality of the i2c-tiny-usb device. Returns
-/// supported read and write flags.
-pub(crate) fn check_device(dev: &impl Connection) -> Result<(ReadFlags, WriteFlags)> {
-    // check the functionality bitmask
-    let mut buf_func = [0u8; 4];
-    dev_read(dev, CMD_GET_FUNC, ReadFlags::empty(), 0, &mut buf_func)?;
-    let func = u32::from_le_bytes(buf_func);
-    if func & I2C_FUNC_I2C == 0 {
-        // the device doesn't support plain I2C (non-SMBUS) transfers
-        return Err(rusb::Error::NotSupported.into());
+fn transfer_once(
+    dev: &impl Connection,
+    options: &TransferOptions,
+    messages: &mut [Message],
+    timeouts: &[Option<Duration>],
+) -> std::result::Result<(), TransferError> {
+    if messages.is_empty() {
+        return Ok(());
+    }
+    let i_message_end = messages.len() - 1; // no underflow because of is_empty() check above
+    for (i_message, message) in messages.iter_mut().enumerate() {
+        let begin = i_message == 0;
+        let end = i_message == i_message_end;
+        let timeout = timeouts
+            .get(i_message)
+            .copied()
+            .flatten()
+            .unwrap_or(options.timeout);
+        transfer_message(dev, options, message, timeout, begin, end).map_err(|source| {
+            TransferError {
+                failed_index: i_message,
+                source,
+            }
+        })?;
     }
 
-    // non-standard I2C transfers are only possible if the device supports protocol mangling
-    let supported_flags = if func & I2C_FUNC_PROTOCOL_MANGLING != 0 {
-        (
-            ReadFlags::NACK | ReadFlags::REVERSE_RW | ReadFlags::NO_START,
-            WriteFlags::IGNORE_NACK | WriteFlags::REVERSE_RW | WriteFlags::NO_START,
-        )
-    } else {
-        Default::default()
-    };
+    Ok(())
+}
 
-    // test the echo command with a bunch of arbitrary values
-    for x in [0u16, 0xaaaa, 0x5555, 0xffff, 0x55aa, 0xaa55, 0x0f0f, 0xf0f0] {
-        let mut buf_echo = [0u8; 2];
-        let req_type = {
-            use rusb::constants::*;
-            LIBUSB_REQUEST_TYPE_VENDOR | LIBUSB_RECIPIENT_INTERFACE | LIBUSB_ENDPOINT_IN
-        };
-        // we cannot use dev_read() for CMD_ECHO since it passes the argument as wValue (normally
-        // used for read flags)
-        let n_read = dev.read_control(req_type, CMD_ECHO, x, 0, &mut buf_echo, TIMEOUT)?;
-        if n_read != 2 || u16::from_le_bytes(buf_echo) != x {
-            return Err(rusb::Error::Other.into());
-        }
+/// Checks that the flags of every message are among the `supported` ones. Without protocol
+/// mangling support the firmware silently ignores flags instead of reporting an error.
+pub(crate) fn check_flags(
+    messages: &[Message],
+    supported: (ReadFlags, WriteFlags),
+) -> std::result::Result<(), TransferError> {
+    let unsupported = messages.iter().position(|message| match message {
+        Message::Read { flags, .. } => !supported.0.contains(*flags),
+        Message::Write { flags, .. } => !supported.1.contains(*flags),
+    });
+    match unsupported {
+        None => Ok(()),
+        Some(failed_index) => Err(TransferError {
+            failed_index,
+            source: rusb::Error::NotSupported.into(),
+        }),
     }
+}
 
-    Ok(supported_flags)
+/// Reads the raw `CMD_GET_STATUS` value describing the outcome of the last I2C operation.
+pub(crate) fn get_status(dev: &impl Connection) -> Result<u8> {
+    get_status_with_timeout(dev, TIMEOUT)
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::connection::mock::MockConnection;
+fn get_status_with_timeout(dev: &impl Connection, timeout: Duration) -> Result<u8> {
+    let mut status: [u8; 1] = [0x0];
+    let n_read = dev_read(
+        dev,
+        CMD_GET_STATUS,
+        ReadFlags::empty(),
+        0,
+        &mut status,
+        timeout,
+    )?;
+    check_len(status.len(), n_read)?;
+    Ok(status[0])
+}
 
-    #[test]
-    fn test_failed_check() {
-        let dev = MockConnection::new();
-        assert!(check_device(&dev).is_err());
+/// Returns the timeout of the next control transfer: the time left until `options.deadline` if
+/// set or `timeout` otherwise. Returns `rusb::Error::Timeout` if the deadline has passed.
+fn next_timeout(options: &TransferOptions, timeout: Duration) -> Result<Duration> {
+    match options.deadline {
+        None => Ok(timeout),
+        Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+            Some(left) if !left.is_zero() => Ok(left),
+            _ => Err(rusb::Error::Timeout.into()),
+        },
     }
+}
 
-    #[test]
-    fn test_check_device() {
-        let dev = MockConnection::new();
-        dev.schedule_read(
-            CMD_GET_FUNC,              // request
-            I2C_M_RD,                  // value
-            0,                         // index
-            &[0x05, 0x00, 0x00, 0x00], // data: I2C + FUNC_PROTOCOL_MANGLING
-        );
-        for x in [0u16, 0xaaaa, 0x5555, 0xffff, 0x55aa, 0xaa55, 0x0f0f, 0xf0f0] {
-            dev.schedule_read(
-                CMD_ECHO,         // request
-                x,                // value
-                0,                // index
-                &x.to_le_bytes(), // data
-            );
+/// Sends a single message of a transfer. `begin` and `end` indicate whether it's the first or last
+/// one in the sequence.
+fn transfer_message(
+    dev: &impl Connection,
+    options: &TransferOptions,
+    message: &mut Message,
+    timeout: Duration,
+    begin: bool,
+    end: bool,
+) -> Result<()> {
+    let op_result = match message {
+        Message::Read {
+            address,
+            data,
+            flags,
+        } => {
+            // Reads longer than `max_read_len` are split into multiple messages. Continuations are
+            // sent after a repeated start to the same address which reads further data from
+            // peripherals with an auto-incrementing read pointer (EEPROMs and most sensors).
+            let chunks = chunk_ranges(data.len(), options.max_read_len);
+            let i_chunk_end = chunks.len() - 1;
+            let mut n_read = 0;
+            chunks
+                .enumerate()
+                .try_for_each(|(i_chunk, range)| {
+                    let is_end = end && i_chunk == i_chunk_end;
+                    let cmd = io_command(begin && i_chunk == 0, is_end);
+                    let chunk_len = range.len();
+                    let timeout = next_timeout(options, timeout)?;
+                    let n = dev_read(dev, cmd, *flags, *address, &mut data[range], timeout)?;
+                    n_read += n;
+                    // stop at the first short chunk since the following data would be misplaced,
+                    // ending the transaction with an empty continuation unless this chunk did
+                    if n < chunk_len && !is_end {
+                        let end_cmd = io_command(false, true);
+                        let _ = dev_read(dev, end_cmd, *flags, *address, &mut [], timeout);
+                    }
+                    check_len(chunk_len, n)
+                })
+                .map_err(|e| match e {
+                    Error::ShortTransfer { .. } => Error::ShortTransfer {
+                        requested: data.len(),
+                        transferred: n_read,
+                    },
+                    e => e,
+                })
         }
-        let (read_flags, write_flags) = check_device(&dev).unwrap();
-        assert!(read_flags.contains(ReadFlags::NACK));
-        assert!(read_flags.contains(ReadFlags::REVERSE_RW));
-        assert!(read_flags.contains(ReadFlags::NO_START));
-        assert!(write_flags.contains(WriteFlags::IGNORE_NACK));
-        assert!(write_flags.contains(WriteFlags::REVERSE_RW));
-        assert!(write_flags.contains(WriteFlags::NO_START));
-    }
+        Message::Write {
+            address,
+            data,
+            flags,
+        } => next_timeout(options, timeout)
+            .and_then(|timeout| {
+                dev_write(dev, io_command(begin, end), *flags, *address, data, timeout)
+            })
+            .and_then(|n_written| check_len(data.len(), n_written)),
+    };
 
-    #[test]
-    fn test_transfer_zero_length() {
-        let dev = MockConnection::new();
-        let mut msgs: [Message; 0] = [];
-        transfer(&dev, &mut msgs).unwrap();
-        assert!(!dev.has_writes(), "no write I2C transactions expected");
+    if !options.check_status {
+        // without the status a NACK of the data can't be told apart from one of the address
+        return match op_result {
+            Err(Error::Usb(rusb::Error::Pipe)) => Err(Error::Nack),
+            result => result,
+        };
     }
 
-    #[test]
-    fn test_transfer_single_write() {
-        let dev = MockConnection::new();
-        dev.schedule_read(CMD_GET_STATUS, I2C_M_RD, 0, &[STATUS_IDLE]);
-        let mut msgs = [Message::Write {
-            address: 0x50,
-            data: &[0x11, 0x22],
-            flags: WriteFlags::empty(),
-        }];
-
-        transfer(&dev, &mut msgs).unwrap();
-        assert!(
-            dev.pop_write(
-                CMD_I2C_IO | CMD_I2C_BEGIN | CMD_I2C_END,
-                0,    // flags for WriteFlags::empty()
-                0x50, // address
-                &[0x11, 0x22]
-            ),
-            "expected write transaction was not found"
-        );
-        assert!(!dev.has_writes(), "no more write I2C transactions expected");
+    // Typically when there is no acknowledgement, the `op_result` will be a failure because the
+    // corresponding USB control transfer is not acknowledged either. We check the status
+    // regardless to distinguish this from other errors and in case there are devices that behave
+    // differently.
+    let status = get_status_with_timeout(dev, next_timeout(options, options.timeout)?)?;
+    match status {
+        STATUS_ADDRESS_NAK => return Err(Error::Nack),
+        STATUS_IDLE | STATUS_ADDRESS_ACK => {}
+        // clones with extended firmware may report other bus errors
+        _ => return Err(Error::BusError { status }),
     }
 
-    #[test]
-    fn test_transfer_single_read() {
-        let dev = MockConnection::new();
-        dev.schedule_read(
-            CMD_I2C_IO | CMD_I2C_BEGIN | CMD_I2C_END,
-            I2C_M_RD,
-            0x50,
-            &[0xAA, 0xBB, 0xCC],
-        );
-        dev.schedule_read(CMD_GET_STATUS, I2C_M_RD, 0, &[STATUS_IDLE]);
-
-        let mut read_buf = [0u8; 3];
-        let mut msgs = [Message::Read {
-            address: 0x50,
-            data: &mut read_buf,
-            flags: ReadFlags::empty(),
-        }];
-        transfer(&dev, &mut msgs).unwrap();
-        assert!(read_buf == [0xAA, 0xBB, 0xCC]);
-        assert!(!dev.has_writes(), "no write I2C transactions expected");
+    // The original firmware reports a NAK in the status when probing an absent peripheral with a
+    // zero-length read. Some clones instead stall or time out the control transfer and leave the
+    // status idle, so that combination is treated as a NACK to make scans behave the same.
+    if let (Message::Read { data, .. }, Err(Error::Usb(rusb::Error::Pipe | rusb::Error::Timeout))) =
+        (&*message, &op_result)
+    {
+        if data.is_empty() && status == STATUS_IDLE {
+            return Err(Error::Nack);
+        }
     }
 
-    #[test]
-    fn test_transfer_two_messages() {
-        let dev = MockConnection::new();
+    // The firmware doesn't report NACKs of individual data bytes but a write that stalls after the
+    // address was acknowledged means the peripheral stopped accepting data. There is no way to tell
+    // how much of the control transfer got through.
+    if let (Message::Write { .. }, Err(Error::Usb(rusb::Error::Pipe))) = (message, &op_result) {
+        if status == STATUS_ADDRESS_ACK {
+            return Err(Error::DataNack);
+        }
+    }
 
-        // response and status for the read message
-        dev.schedule_read(5, I2C_M_RD, 0x10, &[0x01, 0x02]);
-        dev.schedule_read(CMD_GET_STATUS, I2C_M_RD, 0, &[STATUS_IDLE]);
+    // we still want to return an error if there's no NACK but the main operation failed
+    op_result
+}
 
-        // status for the write message
-        dev.schedule_read(CMD_GET_STATUS, I2C_M_RD, 0, &[STATUS_IDLE]);
+/// Maximum number of data bytes in an SMBus block transfer.
+const SMBUS_BLOCK_MAX: usize = 32;
 
-        let mut read_buf = [0u8; 2];
-        let mut msgs = [
+/// Performs an SMBus "block read" of register `reg`. The firmware cannot vary the length of a read
+/// once it has started so the count byte is read first and the whole block is then read in a
+/// second transaction. Returns `rusb::Error::InvalidParam` if the peripheral reports more than 32
+/// bytes and `rusb::Error::Other` if the count changes between the two reads.
+pub(crate) fn smbus_read_block(
+    dev: &impl Connection,
+    options: &TransferOptions,
+    address: u16,
+    reg: u8,
+) -> Result<Vec<u8>> {
+    let mut count = [0u8; 1];
+    transfer(
+        dev,
+        options,
+        &mut [
+            Message::Write {
+                address,
+                data: &[reg],
+                flags: WriteFlags::empty(),
+            },
             Message::Read {
-                address: 0x10,
-                data: &mut read_buf,
+                address,
+                data: &mut count,
                 flags: ReadFlags::empty(),
             },
+        ],
+    )?;
+    let count = count[0] as usize;
+    if count > SMBUS_BLOCK_MAX {
+        return Err(rusb::Error::InvalidParam.into());
+    }
+
+    let mut block = vec![0u8; count + 1];
+    transfer(
+        dev,
+        options,
+        &mut [
+            Message::Write {
+                address,
+                data: &[reg],
+                flags: WriteFlags::empty(),
+            },
+            Message::Read {
+                address,
+                data: &mut block,
+                flags: ReadFlags::empty(),
+            },
+        ],
+    )?;
+    if block[0] as usize != count {
+        return Err(rusb::Error::Other.into());
+    }
+    block.remove(0);
+    Ok(block)
+}
+
+/// Result of probing a single address in [`crate::I2c::scan_detailed`].
+#[derive(Debug, PartialEq, Eq)]
+pub enum ScanResult {
+    /// A peripheral acknowledged the address.
+    Present,
+    /// No acknowledgement.
+    Absent,
+    /// The probe failed for reasons other than a missing acknowledgement.
+    Error(Error),
+}
+
+/// Sends an SMBus "quick" command, i.e. a zero-length read or write at `address`. Returns whether
+/// it was acknowledged.
+pub(crate) fn smbus_quick(
+    dev: &impl Connection,
+    options: &TransferOptions,
+    address: u16,
+    read: bool,
+) -> Result<bool> {
+    let message = if read {
+        Message::Read {
+            address,
+            data: &mut [],
+            flags: ReadFlags::empty(),
+        }
+    } else {
+        Message::Write {
+            address,
+            data: &[],
+            flags: WriteFlags::empty(),
+        }
+    };
+    match transfer(dev, options, &mut [message]) {
+        Ok(()) => Ok(true),
+        Err(Error::Nack) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Writes `data` to the general call address. The address is reserved so this bypasses
+/// [`ReservedAddressPolicy::Reject`]. An [`Error::Nack`] means that no peripheral acknowledged the
+/// call, which is expected when none of them respond to general calls.
+pub(crate) fn general_call(
+    dev: &impl Connection,
+    options: &TransferOptions,
+    data: &[u8],
+) -> Result<()> {
+    let options = TransferOptions {
+        reserved_address_policy: ReservedAddressPolicy::Allow,
+        ..options.clone()
+    };
+    transfer(
+        dev,
+        &options,
+        &mut [Message::Write {
+            address: GENERAL_CALL_ADDRESS,
+            data,
+            flags: WriteFlags::empty(),
+        }],
+    )
+}
+
+/// Tries to release a bus where a peripheral holds SDA low in the middle of a read, the usual fix
+/// being to clock SCL until it finishes. The firmware can't toggle SCL directly but every probe of
+/// an address clocks it nine times. The reserved address 0x7f is probed until it's not
+/// acknowledged, which means SDA was high during the acknowledge bit. Returns `rusb::Error::Busy`
+/// if SDA still appears to be held low afterwards.
+pub(crate) fn recover_bus(dev: &impl Connection, options: &TransferOptions) -> Result<()> {
+    const RECOVERY_ADDRESS: u16 = 0x7f;
+    const MAX_ATTEMPTS: usize = 9;
+    let options = TransferOptions {
+        reserved_address_policy: ReservedAddressPolicy::Allow,
+        ..options.clone()
+    };
+    for _ in 0..MAX_ATTEMPTS {
+        if !smbus_quick(dev, &options, RECOVERY_ADDRESS, true)? {
+            return Ok(());
+        }
+    }
+    Err(rusb::Error::Busy.into())
+}
+
+/// Attempts a zero-length read at each address and returns the ones that acknowledged it. USB
+/// errors other than NACKs are propagated.
+pub(crate) fn scan(
+    dev: &impl Connection,
+    options: &TransferOptions,
+    range: RangeInclusive<u16>,
+) -> Result<Vec<u16>> {
+    let mut found = Vec::new();
+    for address in range {
+        if smbus_quick(dev, options, address, true)? {
+            found.push(address);
+        }
+    }
+    Ok(found)
+}
+
+/// Faster variant of [`scan`] using zero-length writes. When the firmware stalls the control
+/// transfer of a probe that wasn't acknowledged, the address is skipped right away instead of
+/// reading the status as well, which saves one of the two USB round-trips for each empty address.
+/// The downside is that a stall caused by something else is also treated as a missing
+/// peripheral.
+///
+/// `options` are applied the same way as by [`transfer`]: the range is checked against the
+/// reserved address policy, the control transfers use the timeout or deadline and each address
+/// is probed again up to `options.retries` times if it's not acknowledged.
+pub(crate) fn scan_fast(
+    dev: &impl Connection,
+    options: &TransferOptions,
+    range: RangeInclusive<u16>,
+) -> Result<Vec<u16>> {
+    if *range.end() > MAX_ADDRESS
+        || !range
+            .clone()
+            .all(|address| options.reserved_address_policy.allows(address))
+    {
+        return Err(rusb::Error::InvalidParam.into());
+    }
+    let mut found = Vec::new();
+    for address in range {
+        if probe_write(dev, options, address)? {
+            found.push(address);
+        }
+    }
+    Ok(found)
+}
+
+/// Single probe of [`scan_fast`], returns whether `address` was acknowledged.
+fn probe_write(dev: &impl Connection, options: &TransferOptions, address: u16) -> Result<bool> {
+    let mut retries_left = options.retries;
+    loop {
+        let cmd = io_command(true, true);
+        let timeout = next_timeout(options, options.timeout)?;
+        let acked = match dev_write(dev, cmd, WriteFlags::empty(), address, &[], timeout) {
+            Err(Error::Usb(rusb::Error::Pipe)) => false,
+            Err(e) => return Err(e),
+            Ok(_) if !options.check_status => true,
+            Ok(_) => {
+                let timeout = next_timeout(options, options.timeout)?;
+                get_status_with_timeout(dev, timeout)? == STATUS_ADDRESS_ACK
+            }
+        };
+        if acked || retries_left == 0 {
+            return Ok(acked);
+        }
+        retries_left -= 1;
+        std::thread::sleep(options.retry_backoff);
+    }
+}
+
+/// Like [`scan`] but probes every address in `range` regardless of errors and reports the result
+/// of each probe.
+pub(crate) fn scan_detailed(
+    dev: &impl Connection,
+    options: &TransferOptions,
+    range: RangeInclusive<u16>,
+) -> Vec<(u16, ScanResult)> {
+    range
+        .map(|address| {
+            let result = match smbus_quick(dev, options, address, true) {
+                Ok(true) => ScanResult::Present,
+                Ok(false) => ScanResult::Absent,
+                Err(e) => ScanResult::Error(e),
+            };
+            (address, result)
+        })
+        .collect()
+}
+
+/// Issues some test commands and probes the functionality of the i2c-tiny-usb device. Returns
+/// the functionality bitmask reported by the device.
+pub(crate) fn check_device(dev: &impl Connection) -> Result<FunctionFlags> {
+    // check the functionality bitmask
+    let mut buf_func = [0u8; 4];
+    let n_read = dev_read(
+        dev,
+        CMD_GET_FUNC,
+        ReadFlags::empty(),
+        0,
+        &mut buf_func,
+        TIMEOUT,
+    )?;
+    check_len(buf_func.len(), n_read)?;
+    let func = FunctionFlags(u32::from_le_bytes(buf_func));
+    if func.0 & I2C_FUNC_RESERVED != 0 {
+        // garbage, e.g. all ones from a misbehaving clone
+        return Err(Error::InvalidFunctionality(func.0));
+    }
+    if !func.supports_i2c() {
+        // the device doesn't support plain I2C (non-SMBUS) transfers
+        return Err(rusb::Error::NotSupported.into());
+    }
+
+    echo_test(dev)?;
+    check_status(dev)?;
+    Ok(func)
+}
+
+/// Checks that the device reports one of the status values defined by the protocol. Some clones
+/// power up in a broken state that shows up as garbage here. The status of a previous session
+/// may still be reported so any valid value is accepted, not just `STATUS_IDLE`.
+pub(crate) fn check_status(dev: &impl Connection) -> Result<()> {
+    match get_status(dev)? {
+        STATUS_IDLE | STATUS_ADDRESS_ACK | STATUS_ADDRESS_NAK => Ok(()),
+        status => Err(Error::UnexpectedStatus(status)),
+    }
+}
+
+/// Tests the echo command with a bunch of arbitrary values. Returns `rusb::Error::Other` if any of
+/// them is not echoed back correctly.
+pub(crate) fn echo_test(dev: &impl Connection) -> Result<()> {
+    for x in [0u16, 0xaaaa, 0x5555, 0xffff, 0x55aa, 0xaa55, 0x0f0f, 0xf0f0] {
+        if echo(dev, x)? != x {
+            return Err(rusb::Error::Other.into());
+        }
+    }
+    Ok(())
+}
+
+/// Sends `x` with `CMD_ECHO` and returns the value echoed back.
+pub(crate) fn echo(dev: &impl Connection, x: u16) -> Result<u16> {
+    let mut buf_echo = [0u8; 2];
+    let req_type = {
+        use rusb::constants::*;
+        LIBUSB_REQUEST_TYPE_VENDOR | LIBUSB_RECIPIENT_INTERFACE | LIBUSB_ENDPOINT_IN
+    };
+    // we cannot use dev_read() for CMD_ECHO since it passes the argument as wValue (normally
+    // used for read flags)
+    let n_read = dev.read_control(req_type, CMD_ECHO, x, 0, &mut buf_echo, TIMEOUT)?;
+    check_len(buf_echo.len(), n_read)?;
+    Ok(u16::from_le_bytes(buf_echo))
+}
+
+/// Echoes `iterations` pseudo-random values generated from `seed`. Returns
+/// `Error::EchoMismatch` for the first value that is not echoed back correctly.
+pub(crate) fn echo_stress(dev: &impl Connection, iterations: usize, seed: u32) -> Result<()> {
+    // xorshift32, which needs a non-zero state
+    let mut state = seed.max(1);
+    for _ in 0..iterations {
+        state ^= state << 13;
+        state ^= state >> 17;
+        state ^= state << 5;
+        let sent = state as u16;
+        let received = echo(dev, sent)?;
+        if received != sent {
+            return Err(Error::EchoMismatch { sent, received });
+        }
+    }
+    Ok(())
+}
+
+/// Sends an arbitrary vendor request to the interface and reads the response into `buf`. Returns
+/// the number of bytes read.
+pub(crate) fn raw_control_read(
+    dev: &impl Connection,
+    request: u8,
+    value: u16,
+    index: u16,
+    buf: &mut [u8],
+) -> Result<usize> {
+    let req_type = {
+        use rusb::constants::*;
+        LIBUSB_REQUEST_TYPE_VENDOR | LIBUSB_RECIPIENT_INTERFACE | LIBUSB_ENDPOINT_IN
+    };
+    let n_read = dev.read_control(req_type, request, value, index, buf, TIMEOUT)?;
+    if n_read > buf.len() {
+        return Err(rusb::Error::Overflow.into());
+    }
+    Ok(n_read)
+}
+
+/// Sends an arbitrary vendor request with `buf` as data to the interface. Returns the number of
+/// bytes written.
+pub(crate) fn raw_control_write(
+    dev: &impl Connection,
+    request: u8,
+    value: u16,
+    index: u16,
+    buf: &[u8],
+) -> Result<usize> {
+    let req_type = {
+        use rusb::constants::*;
+        LIBUSB_REQUEST_TYPE_VENDOR | LIBUSB_RECIPIENT_INTERFACE | LIBUSB_ENDPOINT_OUT
+    };
+    Ok(dev.write_control(req_type, request, value, index, buf, TIMEOUT)?)
+}
+
+/// Returns whether `request` only queries the adapter without affecting it or the bus.
+pub(crate) fn is_passive_request(request: u8) -> bool {
+    matches!(request, CMD_ECHO | CMD_GET_FUNC | CMD_GET_STATUS)
+}
+
+/// Sets the I2C clock delay of the device. The firmware uses `delay_us` as the duration of half of
+/// the clock period, e.g. the default of 10us corresponds to a 50kHz bus clock.
+pub(crate) fn set_delay(dev: &impl Connection, delay_us: u16) -> Result<()> {
+    if delay_us == 0 {
+        return Err(rusb::Error::InvalidParam.into());
+    }
+    let req_type = {
+        use rusb::constants::*;
+        LIBUSB_REQUEST_TYPE_VENDOR | LIBUSB_RECIPIENT_INTERFACE | LIBUSB_ENDPOINT_OUT
+    };
+    // like CMD_ECHO, the delay is passed as wValue so dev_write() cannot be used here
+    dev.write_control(req_type, CMD_SET_DELAY, delay_us, 0, &[], TIMEOUT)?;
+    Ok(())
+}
+
+/// Converts an I2C clock frequency to the corresponding `CMD_SET_DELAY` value. Rounds up so that
+/// the resulting clock is never faster than requested.
+pub(crate) fn delay_for_frequency(hz: u32) -> Result<u16> {
+    if hz == 0 {
+        return Err(rusb::Error::InvalidParam.into());
+    }
+    let delay_us = 500_000u32.div_ceil(hz);
+    u16::try_from(delay_us).map_err(|_| rusb::Error::InvalidParam.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::mock::{MockConnection, OverReporting};
+
+    #[test]
+    fn test_firmware_from_vendor_product() {
+        assert_eq!(
+            Firmware::from_vendor_product(0x0403, 0xc631),
+            Firmware::Ftdi
+        );
+        assert_eq!(
+            Firmware::from_vendor_product(0x1c40, 0x0534),
+            Firmware::EzPrototypes
+        );
+        assert_eq!(
+            Firmware::from_vendor_product(0x1234, 0x5678),
+            Firmware::Unknown
+        );
+        for (vid, pid) in KNOWN_VENDOR_PRODUCT_IDS {
+            assert_ne!(Firmware::from_vendor_product(vid, pid), Firmware::Unknown);
+        }
+    }
+
+    #[test]
+    fn test_firmware_default_max_read_len() {
+        assert_eq!(Firmware::Ftdi.default_max_read_len(), 256);
+        // i2c-star fails with reads of 102 bytes or more
+        assert!(Firmware::EzPrototypes.default_max_read_len() < 102);
+        assert_eq!(
+            Firmware::Unknown.default_max_read_len(),
+            Firmware::EzPrototypes.default_max_read_len()
+        );
+    }
+
+    #[test]
+    fn test_failed_check() {
+        let dev = MockConnection::new();
+        assert!(check_device(&dev).is_err());
+    }
+
+    #[test]
+    fn test_check_device() {
+        let dev = MockConnection::new();
+        dev.schedule_read(
+            CMD_GET_FUNC,              // request
+            I2C_M_RD,                  // value
+            0,                         // index
+            &[0x05, 0x00, 0x00, 0x00], // data: I2C + FUNC_PROTOCOL_MANGLING
+        );
+        for x in [0u16, 0xaaaa, 0x5555, 0xffff, 0x55aa, 0xaa55, 0x0f0f, 0xf0f0] {
+            dev.schedule_read(
+                CMD_ECHO,         // request
+                x,                // value
+                0,                // index
+                &x.to_le_bytes(), // data
+            );
+        }
+        dev.schedule_read(CMD_GET_STATUS, I2C_M_RD, 0, &[STATUS_IDLE]);
+        let func = check_device(&dev).unwrap();
+        assert!(func.supports_i2c());
+        assert!(func.supports_protocol_mangling());
+        assert!(!func.supports_10bit_addr());
+        let (read_flags, write_flags) = func.transfer_flags();
+        assert!(read_flags.contains(ReadFlags::NACK));
+        assert!(read_flags.contains(ReadFlags::REVERSE_RW));
+        assert!(read_flags.contains(ReadFlags::NO_START));
+        assert!(write_flags.contains(WriteFlags::IGNORE_NACK));
+        assert!(write_flags.contains(WriteFlags::REVERSE_RW));
+        assert!(write_flags.contains(WriteFlags::NO_START));
+    }
+
+    #[test]
+    fn test_check_device_no_protocol_mangling() {
+        let dev = MockConnection::new();
+        dev.schedule_read(CMD_GET_FUNC, I2C_M_RD, 0, &[0x01, 0x00, 0x00, 0x00]); // I2C only
+        for x in [0u16, 0xaaaa, 0x5555, 0xffff, 0x55aa, 0xaa55, 0x0f0f, 0xf0f0] {
+            dev.schedule_read(CMD_ECHO, x, 0, &x.to_le_bytes());
+        }
+        dev.schedule_read(CMD_GET_STATUS, I2C_M_RD, 0, &[STATUS_IDLE]);
+        let func = check_device(&dev).unwrap();
+        assert!(func.supports_i2c());
+        assert!(!func.supports_protocol_mangling());
+        let (read_flags, write_flags) = func.transfer_flags();
+        assert!(read_flags.is_empty());
+        assert!(write_flags.is_empty());
+        dev.verify();
+    }
+
+    #[test]
+    fn test_check_device_invalid_functionality() {
+        for func in [0xffffffffu32, 0x00000041, 0x80000001] {
+            let dev = MockConnection::new();
+            dev.schedule_read(CMD_GET_FUNC, I2C_M_RD, 0, &func.to_le_bytes());
+            assert_eq!(check_device(&dev), Err(Error::InvalidFunctionality(func)));
+            dev.verify();
+        }
+    }
+
+    #[test]
+    fn test_check_status() {
+        let dev = MockConnection::new();
+        dev.schedule_read(CMD_GET_STATUS, I2C_M_RD, 0, &[STATUS_ADDRESS_NAK]);
+        dev.schedule_read(CMD_GET_STATUS, I2C_M_RD, 0, &[0xff]);
+        assert_eq!(check_status(&dev), Ok(()));
+        assert_eq!(check_status(&dev), Err(Error::UnexpectedStatus(0xff)));
+    }
+
+    #[test]
+    fn test_echo_test_mismatch() {
+        let dev = MockConnection::new();
+        dev.schedule_read(CMD_ECHO, 0, 0, &[0x00, 0x00]);
+        dev.schedule_read(CMD_ECHO, 0xaaaa, 0, &[0xaa, 0x55]);
+        assert_eq!(echo_test(&dev), Err(Error::Usb(rusb::Error::Other)));
+    }
+
+    #[test]
+    fn test_echo() {
+        let dev = MockConnection::new();
+        dev.schedule_read(CMD_ECHO, 0x1234, 0, &[0x34, 0x12]);
+        assert_eq!(echo(&dev, 0x1234), Ok(0x1234));
+    }
+
+    #[test]
+    fn test_echo_stress() {
+        let dev = MockConnection::new();
+        // first two values of xorshift32 seeded with 1
+        dev.schedule_read(CMD_ECHO, 0x2021, 0, &[0x21, 0x20]);
+        dev.schedule_read(CMD_ECHO, 0x0601, 0, &[0x01, 0x06]);
+        assert_eq!(echo_stress(&dev, 2, 1), Ok(()));
+        dev.verify();
+
+        dev.schedule_read(CMD_ECHO, 0x2021, 0, &[0x21, 0x00]);
+        assert_eq!(
+            echo_stress(&dev, 2, 1),
+            Err(Error::EchoMismatch {
+                sent: 0x2021,
+                received: 0x0021
+            })
+        );
+    }
+
+    #[test]
+    fn test_get_status() {
+        let dev = MockConnection::new();
+        dev.schedule_read(CMD_GET_STATUS, I2C_M_RD, 0, &[STATUS_ADDRESS_ACK]);
+        assert_eq!(get_status(&dev), Ok(STATUS_ADDRESS_ACK));
+        assert!(get_status(&dev).is_err());
+    }
+
+    #[test]
+    fn test_request_direction() {
+        use rusb::constants::LIBUSB_ENDPOINT_IN;
+        let dev = MockConnection::new();
+        dev.schedule_read(CMD_GET_STATUS, I2C_M_RD, 0, &[STATUS_IDLE]);
+        let n = dev_write(&dev, CMD_I2C_IO, WriteFlags::empty(), 0x50, &[1], TIMEOUT).unwrap();
+        assert_eq!(n, 1);
+        assert_eq!(get_status(&dev), Ok(STATUS_IDLE));
+        let writes = dev.last_writes.borrow();
+        assert_eq!(writes[0].request_type & LIBUSB_ENDPOINT_IN, 0);
+    }
+
+    #[test]
+    fn test_transfer_zero_length() {
+        let dev = MockConnection::new();
+        let mut msgs: [Message; 0] = [];
+        transfer(&dev, &TransferOptions::default(), &mut msgs).unwrap();
+        assert!(!dev.has_writes(), "no write I2C transactions expected");
+    }
+
+    #[test]
+    fn test_transfer_single_write() {
+        let dev = MockConnection::new();
+        dev.schedule_read(CMD_GET_STATUS, I2C_M_RD, 0, &[STATUS_IDLE]);
+        let mut msgs = [Message::Write {
+            address: 0x50,
+            data: &[0x11, 0x22],
+            flags: WriteFlags::empty(),
+        }];
+
+        transfer(&dev, &TransferOptions::default(), &mut msgs).unwrap();
+        assert!(
+            dev.pop_write(
+                CMD_I2C_IO | CMD_I2C_BEGIN | CMD_I2C_END,
+                0,    // flags for WriteFlags::empty()
+                0x50, // address
+                &[0x11, 0x22]
+            ),
+            "expected write transaction was not found"
+        );
+        assert!(!dev.has_writes(), "no more write I2C transactions expected");
+    }
+
+    #[test]
+    fn test_transfer_single_read() {
+        let dev = MockConnection::new();
+        dev.schedule_read(
+            CMD_I2C_IO | CMD_I2C_BEGIN | CMD_I2C_END,
+            I2C_M_RD,
+            0x50,
+            &[0xAA, 0xBB, 0xCC],
+        );
+        dev.schedule_read(CMD_GET_STATUS, I2C_M_RD, 0, &[STATUS_IDLE]);
+
+        let mut read_buf = [0u8; 3];
+        let mut msgs = [Message::Read {
+            address: 0x50,
+            data: &mut read_buf,
+            flags: ReadFlags::empty(),
+        }];
+        transfer(&dev, &TransferOptions::default(), &mut msgs).unwrap();
+        assert!(read_buf == [0xAA, 0xBB, 0xCC]);
+        assert!(!dev.has_writes(), "no write I2C transactions expected");
+    }
+
+    #[test]
+    fn test_transfer_two_messages() {
+        let dev = MockConnection::new();
+
+        // response and status for the read message
+        dev.schedule_read(5, I2C_M_RD, 0x10, &[0x01, 0x02]);
+        dev.schedule_read(CMD_GET_STATUS, I2C_M_RD, 0, &[STATUS_IDLE]);
+
+        // status for the write message
+        dev.schedule_read(CMD_GET_STATUS, I2C_M_RD, 0, &[STATUS_IDLE]);
+
+        let mut read_buf = [0u8; 2];
+        let mut msgs = [
+            Message::Read {
+                address: 0x10,
+                data: &mut read_buf,
+                flags: ReadFlags::empty(),
+            },
+            Message::Write {
+                address: 0x20,
+                data: &[0xAA, 0xBB, 0xCC],
+                flags: WriteFlags::empty(),
+            },
+        ];
+
+        transfer(&dev, &TransferOptions::default(), &mut msgs).unwrap();
+        assert_eq!(read_buf, [0x01, 0x02]);
+        assert!(dev.pop_write(
+            CMD_I2C_IO | CMD_I2C_END, // = 6
+            0,                        // WriteFlags::empty
+            0x20,                     // address
+            &[0xAA, 0xBB, 0xCC]       // data
+        ));
+        assert!(!dev.has_writes(), "no more write I2C transactions expected");
+    }
+
+    #[test]
+    fn test_transfer_with_timeouts() {
+        let dev = MockConnection::new();
+        dev.schedule_read(CMD_GET_STATUS, I2C_M_RD, 0, &[STATUS_ADDRESS_ACK]);
+        dev.schedule_read(CMD_I2C_IO | CMD_I2C_END, I2C_M_RD, 0x40, &[0x12]);
+        dev.schedule_read(CMD_GET_STATUS, I2C_M_RD, 0, &[STATUS_IDLE]);
+
+        let slow = Duration::from_secs(5);
+        let mut read_buf = [0u8; 1];
+        let mut msgs = [
+            Message::Write {
+                address: 0x40,
+                data: &[0xF3],
+                flags: WriteFlags::empty(),
+            },
+            Message::Read {
+                address: 0x40,
+                data: &mut read_buf,
+                flags: ReadFlags::empty(),
+            },
+        ];
+        transfer_with_timeouts(
+            &dev,
+            &TransferOptions::default(),
+            &mut msgs,
+            &[None, Some(slow)],
+        )
+        .unwrap();
+        assert_eq!(read_buf, [0x12]);
+        assert_eq!(
+            *dev.timeouts.borrow(),
+            [
+                (CMD_I2C_IO | CMD_I2C_BEGIN, TIMEOUT),
+                (CMD_GET_STATUS, TIMEOUT),
+                (CMD_I2C_IO | CMD_I2C_END, slow),
+                (CMD_GET_STATUS, TIMEOUT),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_transfer_deadline() {
+        let dev = MockConnection::new();
+        let mut msgs = [Message::Write {
+            address: 0x50,
+            data: &[0x01],
+            flags: WriteFlags::empty(),
+        }];
+        let past = TransferOptions {
+            deadline: Some(Instant::now()),
+            ..Default::default()
+        };
+        assert_eq!(
+            transfer(&dev, &past, &mut msgs),
+            Err(Error::Usb(rusb::Error::Timeout))
+        );
+        assert!(dev.timeouts.borrow().is_empty());
+
+        dev.schedule_read(CMD_GET_STATUS, I2C_M_RD, 0, &[STATUS_ADDRESS_ACK]);
+        let future = TransferOptions {
+            deadline: Some(Instant::now() + Duration::from_secs(60)),
+            ..Default::default()
+        };
+        transfer(&dev, &future, &mut msgs).unwrap();
+        let timeouts = dev.timeouts.borrow();
+        assert_eq!(timeouts.len(), 2);
+        assert!(timeouts.iter().all(|(_, timeout)| *timeout > TIMEOUT));
+    }
+
+    #[test]
+    fn test_merge_groups() {
+        let mut buf = [0u8; 2];
+        let msgs = [
+            Message::Write {
+                address: 0x50,
+                data: &[0x01],
+                flags: WriteFlags::empty(),
+            },
+            Message::Write {
+                address: 0x50,
+                data: &[0x02],
+                flags: WriteFlags::empty(),
+            },
+            Message::Write {
+                address: 0x51,
+                data: &[0x03],
+                flags: WriteFlags::empty(),
+            },
+            Message::Write {
+                address: 0x51,
+                data: &[0x04],
+                flags: WriteFlags::NO_START,
+            },
+            Message::Read {
+                address: 0x51,
+                data: &mut buf,
+                flags: ReadFlags::empty(),
+            },
+        ];
+        assert_eq!(merge_groups(&msgs), vec![0..2, 2..3, 3..4, 4..5]);
+        assert!(merge_groups(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_transfer_merged() {
+        let dev = MockConnection::new();
+        dev.expect_write(CMD_I2C_IO | CMD_I2C_BEGIN, 0, 0x50, &[0x01, 0x02, 0x03]);
+        dev.schedule_read(CMD_GET_STATUS, I2C_M_RD, 0, &[STATUS_ADDRESS_ACK]);
+        dev.schedule_read(
+            CMD_I2C_IO | CMD_I2C_END,
+            I2C_M_RD,
+            0x50,
+            &[0xaa, 0xbb, 0xcc],
+        );
+        dev.schedule_read(CMD_GET_STATUS, I2C_M_RD, 0, &[STATUS_IDLE]);
+
+        let options = TransferOptions {
+            merge_adjacent: true,
+            ..Default::default()
+        };
+        let (mut buf1, mut buf2) = ([0u8; 1], [0u8; 2]);
+        let mut msgs = [
+            Message::Write {
+                address: 0x50,
+                data: &[0x01],
+                flags: WriteFlags::empty(),
+            },
+            Message::Write {
+                address: 0x50,
+                data: &[0x02, 0x03],
+                flags: WriteFlags::empty(),
+            },
+            Message::Read {
+                address: 0x50,
+                data: &mut buf1,
+                flags: ReadFlags::empty(),
+            },
+            Message::Read {
+                address: 0x50,
+                data: &mut buf2,
+                flags: ReadFlags::empty(),
+            },
+        ];
+        transfer(&dev, &options, &mut msgs).unwrap();
+        dev.verify();
+        assert_eq!(buf1, [0xaa]);
+        assert_eq!(buf2, [0xbb, 0xcc]);
+    }
+
+    #[test]
+    fn test_transfer_merged_failed_index() {
+        let dev = MockConnection::new();
+        dev.schedule_read(CMD_GET_STATUS, I2C_M_RD, 0, &[STATUS_ADDRESS_ACK]);
+        dev.schedule_read(CMD_GET_STATUS, I2C_M_RD, 0, &[STATUS_ADDRESS_NAK]);
+        let options = TransferOptions {
+            merge_adjacent: true,
+            ..Default::default()
+        };
+        let mut msgs = [
+            Message::Write {
+                address: 0x50,
+                data: &[0x01],
+                flags: WriteFlags::empty(),
+            },
+            Message::Write {
+                address: 0x50,
+                data: &[0x02],
+                flags: WriteFlags::empty(),
+            },
+            Message::Write {
+                address: 0x51,
+                data: &[0x03],
+                flags: WriteFlags::empty(),
+            },
+        ];
+        let err = transfer_detailed(&dev, &options, &mut msgs).unwrap_err();
+        assert_eq!(err.failed_index, 2);
+        assert_eq!(err.source, Error::Nack);
+    }
+
+    #[test]
+    fn test_transfer_no_status_check() {
+        let dev = MockConnection::new();
+        let options = TransferOptions {
+            check_status: false,
+            ..Default::default()
+        };
+        dev.expect_write(CMD_I2C_IO | CMD_I2C_BEGIN, 0, 0x50, &[0x11]);
+        dev.schedule_read(CMD_I2C_IO | CMD_I2C_END, I2C_M_RD, 0x50, &[0xaa]);
+        let mut buf = [0u8; 1];
+        let mut msgs = [
+            Message::Write {
+                address: 0x50,
+                data: &[0x11],
+                flags: WriteFlags::empty(),
+            },
+            Message::Read {
+                address: 0x50,
+                data: &mut buf,
+                flags: ReadFlags::empty(),
+            },
+        ];
+        transfer(&dev, &options, &mut msgs).unwrap();
+        dev.verify();
+        assert!(matches!(&msgs[1], Message::Read { data, .. } if data[..] == [0xaa]));
+
+        dev.fail_next_write(rusb::Error::Pipe);
+        assert_eq!(transfer(&dev, &options, &mut msgs[..1]), Err(Error::Nack));
+        dev.verify();
+    }
+
+    #[test]
+    fn test_transfer_chunked_read() {
+        let dev = MockConnection::new();
+        let options = TransferOptions {
+            max_read_len: 2,
+            ..Default::default()
+        };
+        dev.schedule_read(CMD_I2C_IO | CMD_I2C_BEGIN, I2C_M_RD, 0x50, &[0x01, 0x02]);
+        dev.schedule_read(CMD_I2C_IO, I2C_M_RD, 0x50, &[0x03, 0x04]);
+        dev.schedule_read(CMD_I2C_IO | CMD_I2C_END, I2C_M_RD, 0x50, &[0x05]);
+        dev.schedule_read(CMD_GET_STATUS, I2C_M_RD, 0, &[STATUS_IDLE]);
+
+        let mut read_buf = [0u8; 5];
+        let mut msgs = [Message::Read {
+            address: 0x50,
+            data: &mut read_buf,
+            flags: ReadFlags::empty(),
+        }];
+        transfer(&dev, &options, &mut msgs).unwrap();
+        assert_eq!(read_buf, [0x01, 0x02, 0x03, 0x04, 0x05]);
+        assert!(!dev.has_writes(), "no write I2C transactions expected");
+    }
+
+    #[test]
+    fn test_transfer_short_chunked_read() {
+        let dev = MockConnection::new();
+        let options = TransferOptions {
+            max_read_len: 2,
+            ..Default::default()
+        };
+        dev.schedule_read(CMD_I2C_IO | CMD_I2C_BEGIN, I2C_M_RD, 0x50, &[0x01, 0x02]);
+        dev.schedule_read(CMD_I2C_IO, I2C_M_RD, 0x50, &[0x03]);
+        // the transaction is ended instead of reading the last chunk
+        dev.schedule_read(CMD_I2C_IO | CMD_I2C_END, I2C_M_RD, 0x50, &[]);
+        dev.schedule_read(CMD_GET_STATUS, I2C_M_RD, 0, &[STATUS_ADDRESS_ACK]);
+
+        let mut read_buf = [0u8; 5];
+        let mut msgs = [Message::Read {
+            address: 0x50,
+            data: &mut read_buf,
+            flags: ReadFlags::empty(),
+        }];
+        assert_eq!(
+            transfer(&dev, &options, &mut msgs),
+            Err(Error::ShortTransfer {
+                requested: 5,
+                transferred: 3
+            })
+        );
+        dev.verify();
+    }
+
+    #[test]
+    fn test_transfer_over_read() {
+        let dev = MockConnection::new();
+        dev.schedule_read(7, I2C_M_RD, 0x50, &[0x01, 0x02, 0x03]);
+        dev.schedule_read(CMD_GET_STATUS, I2C_M_RD, 0, &[STATUS_ADDRESS_ACK]);
+
+        let mut read_buf = [0u8; 2];
+        let mut msgs = [Message::Read {
+            address: 0x50,
+            data: &mut read_buf,
+            flags: ReadFlags::empty(),
+        }];
+        assert_eq!(
+            transfer(&dev, &TransferOptions::default(), &mut msgs),
+            Err(Error::Usb(rusb::Error::Overflow))
+        );
+        assert_eq!(check_len(2, 3), Err(Error::Usb(rusb::Error::Overflow)));
+    }
+
+    #[test]
+    fn test_transfer_long_write_not_chunked() {
+        let dev = MockConnection::new();
+        let options = TransferOptions {
+            max_read_len: 2,
+            ..Default::default()
+        };
+        dev.schedule_read(CMD_GET_STATUS, I2C_M_RD, 0, &[STATUS_IDLE]);
+        let mut msgs = [Message::Write {
+            address: 0x50,
+            data: &[0x11, 0x22, 0x33, 0x44],
+            flags: WriteFlags::empty(),
+        }];
+
+        transfer(&dev, &options, &mut msgs).unwrap();
+        assert!(dev.pop_write(
+            CMD_I2C_IO | CMD_I2C_BEGIN | CMD_I2C_END,
+            0,
+            0x50,
+            &[0x11, 0x22, 0x33, 0x44]
+        ));
+        assert!(!dev.has_writes(), "no more write I2C transactions expected");
+    }
+
+    #[test]
+    fn test_transfer_address_nack() {
+        let dev = MockConnection::new();
+        dev.schedule_read(CMD_GET_STATUS, I2C_M_RD, 0, &[STATUS_ADDRESS_NAK]);
+        let mut msgs = [Message::Write {
+            address: 0x03,
+            data: &[0x11],
+            flags: WriteFlags::empty(),
+        }];
+        assert_eq!(
+            transfer(&dev, &TransferOptions::default(), &mut msgs),
+            Err(Error::Nack)
+        );
+    }
+
+    #[test]
+    fn test_transfer_read_error() {
+        let dev = MockConnection::new();
+        dev.schedule_read_error(
+            CMD_I2C_IO | CMD_I2C_BEGIN | CMD_I2C_END,
+            I2C_M_RD,
+            0x50,
+            rusb::Error::Timeout,
+        );
+        dev.schedule_read(CMD_GET_STATUS, I2C_M_RD, 0, &[STATUS_ADDRESS_ACK]);
+        let mut read_buf = [0u8; 2];
+        let mut msgs = [Message::Read {
+            address: 0x50,
+            data: &mut read_buf,
+            flags: ReadFlags::empty(),
+        }];
+        assert_eq!(
+            transfer(&dev, &TransferOptions::default(), &mut msgs),
+            Err(Error::Usb(rusb::Error::Timeout))
+        );
+    }
+
+    #[test]
+    fn test_transfer_zero_length_read_stall() {
+        let dev = MockConnection::new();
+        dev.schedule_read_error(
+            CMD_I2C_IO | CMD_I2C_BEGIN | CMD_I2C_END,
+            I2C_M_RD,
+            0x50,
+            rusb::Error::Pipe,
+        );
+        dev.schedule_read(CMD_GET_STATUS, I2C_M_RD, 0, &[STATUS_IDLE]);
+        let mut msgs = [Message::Read {
+            address: 0x50,
+            data: &mut [],
+            flags: ReadFlags::empty(),
+        }];
+        assert_eq!(
+            transfer(&dev, &TransferOptions::default(), &mut msgs),
+            Err(Error::Nack)
+        );
+    }
+
+    #[test]
+    fn test_transfer_read_nack() {
+        let dev = MockConnection::new();
+        dev.schedule_read_error(
+            CMD_I2C_IO | CMD_I2C_BEGIN | CMD_I2C_END,
+            I2C_M_RD,
+            0x50,
+            rusb::Error::Pipe,
+        );
+        dev.schedule_read(CMD_GET_STATUS, I2C_M_RD, 0, &[STATUS_ADDRESS_NAK]);
+        let mut read_buf = [0u8; 2];
+        let mut msgs = [Message::Read {
+            address: 0x50,
+            data: &mut read_buf,
+            flags: ReadFlags::empty(),
+        }];
+        assert_eq!(
+            transfer(&dev, &TransferOptions::default(), &mut msgs),
+            Err(Error::Nack)
+        );
+    }
+
+    #[test]
+    fn test_transfer_bus_error() {
+        let dev = MockConnection::new();
+        dev.schedule_read(CMD_GET_STATUS, I2C_M_RD, 0, &[0x03]);
+        let mut msgs = [Message::Write {
+            address: 0x50,
+            data: &[0x11],
+            flags: WriteFlags::empty(),
+        }];
+        assert_eq!(
+            transfer(&dev, &TransferOptions::default(), &mut msgs),
+            Err(Error::BusError { status: 0x03 })
+        );
+    }
+
+    #[test]
+    fn test_transfer_data_nack() {
+        let dev = MockConnection::new();
+        dev.fail_next_write(rusb::Error::Pipe);
+        dev.schedule_read(CMD_GET_STATUS, I2C_M_RD, 0, &[STATUS_ADDRESS_ACK]);
+        let mut msgs = [Message::Write {
+            address: 0x50,
+            data: &[0x11, 0x22],
+            flags: WriteFlags::empty(),
+        }];
+        assert_eq!(
+            transfer(&dev, &TransferOptions::default(), &mut msgs),
+            Err(Error::DataNack)
+        );
+        assert!(!dev.has_writes());
+    }
+
+    #[test]
+    fn test_check_flags() {
+        let supported = (ReadFlags::empty(), WriteFlags::IGNORE_NACK);
+        let mut read_buf = [0u8; 1];
+        let mut msgs = [
             Message::Write {
-                address: 0x20,
-                data: &[0xAA, 0xBB, 0xCC],
+                address: 0x50,
+                data: &[0x11],
+                flags: WriteFlags::IGNORE_NACK,
+            },
+            Message::Read {
+                address: 0x50,
+                data: &mut read_buf,
+                flags: ReadFlags::NO_START,
+            },
+        ];
+        assert_eq!(check_flags(&msgs[..1], supported), Ok(()));
+        assert_eq!(
+            check_flags(&msgs, supported),
+            Err(TransferError {
+                failed_index: 1,
+                source: Error::Usb(rusb::Error::NotSupported),
+            })
+        );
+        if let Message::Read { flags, .. } = &mut msgs[1] {
+            *flags = ReadFlags::empty();
+        }
+        assert_eq!(check_flags(&msgs, supported), Ok(()));
+    }
+
+    #[test]
+    fn test_transfer_invalid_address() {
+        let dev = MockConnection::new();
+        let mut msgs = [
+            Message::Write {
+                address: 0x50,
+                data: &[0x11],
+                flags: WriteFlags::empty(),
+            },
+            Message::Write {
+                address: 0x80,
+                data: &[0x22],
                 flags: WriteFlags::empty(),
             },
         ];
+        assert_eq!(
+            transfer_detailed(&dev, &TransferOptions::default(), &mut msgs),
+            Err(TransferError {
+                failed_index: 1,
+                source: Error::Usb(rusb::Error::InvalidParam),
+            })
+        );
+        dev.verify();
+    }
 
-        transfer(&dev, &mut msgs).unwrap();
-        assert_eq!(read_buf, [0x01, 0x02]);
-        assert!(dev.pop_write(
-            CMD_I2C_IO | CMD_I2C_END, // = 6
-            0,                        // WriteFlags::empty
-            0x20,                     // address
-            &[0xAA, 0xBB, 0xCC]       // data
-        ));
+    #[test]
+    fn test_transfer_no_start_first() {
+        let dev = MockConnection::new();
+        let mut buf = [0u8; 1];
+        let mut msgs = [
+            Message::Read {
+                address: 0x50,
+                data: &mut buf,
+                flags: ReadFlags::NO_START,
+            },
+            Message::Write {
+                address: 0x50,
+                data: &[0x11],
+                flags: WriteFlags::NO_START,
+            },
+        ];
+        assert_eq!(
+            transfer_detailed(&dev, &TransferOptions::default(), &mut msgs),
+            Err(TransferError {
+                failed_index: 0,
+                source: Error::Usb(rusb::Error::InvalidParam),
+            })
+        );
+        assert_eq!(
+            transfer_detailed(&dev, &TransferOptions::default(), &mut msgs[1..]),
+            Err(TransferError {
+                failed_index: 0,
+                source: Error::Usb(rusb::Error::InvalidParam),
+            })
+        );
+        dev.verify();
+
+        // fine on the following messages
+        dev.expect_write(CMD_I2C_IO | CMD_I2C_BEGIN, 0, 0x50, &[0x11]);
+        dev.schedule_read(CMD_GET_STATUS, I2C_M_RD, 0, &[STATUS_ADDRESS_ACK]);
+        dev.expect_write(CMD_I2C_IO | CMD_I2C_END, I2C_M_NOSTART, 0x50, &[0x22]);
+        dev.schedule_read(CMD_GET_STATUS, I2C_M_RD, 0, &[STATUS_ADDRESS_ACK]);
+        let mut msgs = [
+            Message::Write {
+                address: 0x50,
+                data: &[0x11],
+                flags: WriteFlags::empty(),
+            },
+            Message::Write {
+                address: 0x50,
+                data: &[0x22],
+                flags: WriteFlags::NO_START,
+            },
+        ];
+        transfer(&dev, &TransferOptions::default(), &mut msgs).unwrap();
+        dev.verify();
+    }
+
+    #[test]
+    fn test_transfer_reserved_address() {
+        let dev = MockConnection::new();
+        let options = TransferOptions {
+            reserved_address_policy: ReservedAddressPolicy::Reject,
+            ..Default::default()
+        };
+        for address in [0x00, 0x02, 0x78, 0x7f] {
+            let mut msgs = [Message::Write {
+                address,
+                data: &[0x11],
+                flags: WriteFlags::empty(),
+            }];
+            assert_eq!(
+                transfer(&dev, &options, &mut msgs),
+                Err(Error::Usb(rusb::Error::InvalidParam))
+            );
+        }
+        dev.verify();
+    }
+
+    #[test]
+    fn test_transfer_retry() {
+        let dev = MockConnection::new();
+        let options = TransferOptions {
+            retries: 2,
+            ..Default::default()
+        };
+        dev.schedule_read(CMD_GET_STATUS, I2C_M_RD, 0, &[STATUS_ADDRESS_NAK]);
+        dev.schedule_read(CMD_GET_STATUS, I2C_M_RD, 0, &[STATUS_ADDRESS_NAK]);
+        dev.schedule_read(CMD_GET_STATUS, I2C_M_RD, 0, &[STATUS_IDLE]);
+        let mut msgs = [Message::Write {
+            address: 0x50,
+            data: &[0x11],
+            flags: WriteFlags::empty(),
+        }];
+
+        transfer(&dev, &options, &mut msgs).unwrap();
+        for _ in 0..3 {
+            assert!(dev.pop_write(CMD_I2C_IO | CMD_I2C_BEGIN | CMD_I2C_END, 0, 0x50, &[0x11]));
+        }
+        assert!(!dev.has_writes(), "no more write I2C transactions expected");
+    }
+
+    #[test]
+    fn test_transfer_retries_exhausted() {
+        let dev = MockConnection::new();
+        let options = TransferOptions {
+            retries: 1,
+            ..Default::default()
+        };
+        dev.schedule_read(CMD_GET_STATUS, I2C_M_RD, 0, &[STATUS_ADDRESS_NAK]);
+        dev.schedule_read(CMD_GET_STATUS, I2C_M_RD, 0, &[STATUS_ADDRESS_NAK]);
+        let mut msgs = [Message::Write {
+            address: 0x50,
+            data: &[0x11],
+            flags: WriteFlags::empty(),
+        }];
+        assert_eq!(transfer(&dev, &options, &mut msgs), Err(Error::Nack));
+    }
+
+    #[test]
+    fn test_smbus_read_block() {
+        let dev = MockConnection::new();
+        dev.schedule_read(CMD_GET_STATUS, I2C_M_RD, 0, &[STATUS_IDLE]);
+        dev.schedule_read(CMD_I2C_IO | CMD_I2C_END, I2C_M_RD, 0x0b, &[0x02]);
+        dev.schedule_read(CMD_GET_STATUS, I2C_M_RD, 0, &[STATUS_IDLE]);
+        dev.schedule_read(CMD_GET_STATUS, I2C_M_RD, 0, &[STATUS_IDLE]);
+        dev.schedule_read(
+            CMD_I2C_IO | CMD_I2C_END,
+            I2C_M_RD,
+            0x0b,
+            &[0x02, 0xaa, 0xbb],
+        );
+        dev.schedule_read(CMD_GET_STATUS, I2C_M_RD, 0, &[STATUS_IDLE]);
+
+        dev.expect_write(CMD_I2C_IO | CMD_I2C_BEGIN, 0, 0x0b, &[0x20]);
+        dev.expect_write(CMD_I2C_IO | CMD_I2C_BEGIN, 0, 0x0b, &[0x20]);
+
+        let block = smbus_read_block(&dev, &TransferOptions::default(), 0x0b, 0x20).unwrap();
+        assert_eq!(block, [0xaa, 0xbb]);
+        dev.verify();
+    }
+
+    #[test]
+    fn test_smbus_read_block_too_long() {
+        let dev = MockConnection::new();
+        dev.schedule_read(CMD_GET_STATUS, I2C_M_RD, 0, &[STATUS_IDLE]);
+        dev.schedule_read(CMD_I2C_IO | CMD_I2C_END, I2C_M_RD, 0x0b, &[33]);
+        dev.schedule_read(CMD_GET_STATUS, I2C_M_RD, 0, &[STATUS_IDLE]);
+        assert_eq!(
+            smbus_read_block(&dev, &TransferOptions::default(), 0x0b, 0x20),
+            Err(Error::Usb(rusb::Error::InvalidParam))
+        );
+    }
+
+    #[test]
+    fn test_smbus_quick_write() {
+        let dev = MockConnection::new();
+        dev.schedule_read(CMD_GET_STATUS, I2C_M_RD, 0, &[STATUS_ADDRESS_ACK]);
+        dev.schedule_read(CMD_GET_STATUS, I2C_M_RD, 0, &[STATUS_ADDRESS_NAK]);
+        dev.expect_write(CMD_I2C_IO | CMD_I2C_BEGIN | CMD_I2C_END, 0, 0x0c, &[]);
+        dev.expect_write(CMD_I2C_IO | CMD_I2C_BEGIN | CMD_I2C_END, 0, 0x0d, &[]);
+        let options = TransferOptions::default();
+        assert_eq!(smbus_quick(&dev, &options, 0x0c, false), Ok(true));
+        assert_eq!(smbus_quick(&dev, &options, 0x0d, false), Ok(false));
+        dev.verify();
+    }
+
+    #[test]
+    fn test_general_call() {
+        let dev = MockConnection::new();
+        dev.expect_write(CMD_I2C_IO | CMD_I2C_BEGIN | CMD_I2C_END, 0, 0x00, &[0x06]);
+        dev.schedule_read(CMD_GET_STATUS, I2C_M_RD, 0, &[STATUS_ADDRESS_ACK]);
+        let options = TransferOptions {
+            reserved_address_policy: ReservedAddressPolicy::Reject,
+            ..Default::default()
+        };
+        general_call(&dev, &options, &[0x06]).unwrap();
+        dev.verify();
+    }
+
+    #[test]
+    fn test_recover_bus() {
+        let dev = MockConnection::new();
+        for status in [STATUS_ADDRESS_ACK, STATUS_ADDRESS_ACK, STATUS_ADDRESS_NAK] {
+            dev.schedule_read(
+                CMD_I2C_IO | CMD_I2C_BEGIN | CMD_I2C_END,
+                I2C_M_RD,
+                0x7f,
+                &[],
+            );
+            dev.schedule_read(CMD_GET_STATUS, I2C_M_RD, 0, &[status]);
+        }
+        assert_eq!(recover_bus(&dev, &TransferOptions::default()), Ok(()));
+        dev.verify();
+    }
+
+    #[test]
+    fn test_recover_bus_busy() {
+        let dev = MockConnection::new();
+        for _ in 0..9 {
+            dev.schedule_read(
+                CMD_I2C_IO | CMD_I2C_BEGIN | CMD_I2C_END,
+                I2C_M_RD,
+                0x7f,
+                &[],
+            );
+            dev.schedule_read(CMD_GET_STATUS, I2C_M_RD, 0, &[STATUS_ADDRESS_ACK]);
+        }
+        // the probed address is reserved but that doesn't matter here, the timeout does
+        let options = TransferOptions {
+            reserved_address_policy: ReservedAddressPolicy::Reject,
+            timeout: Duration::from_millis(50),
+            ..Default::default()
+        };
+        assert_eq!(
+            recover_bus(&dev, &options),
+            Err(Error::Usb(rusb::Error::Busy))
+        );
+        dev.verify();
+        let timeouts = dev.timeouts.borrow();
+        assert_eq!(timeouts.len(), 18);
+        assert!(timeouts
+            .iter()
+            .all(|&(_, timeout)| timeout == Duration::from_millis(50)));
+    }
+
+    #[test]
+    fn test_scan() {
+        let dev = MockConnection::new();
+        let io = CMD_I2C_IO | CMD_I2C_BEGIN | CMD_I2C_END;
+        dev.schedule_read(io, I2C_M_RD, 0x10, &[]);
+        dev.schedule_read(CMD_GET_STATUS, I2C_M_RD, 0, &[STATUS_ADDRESS_NAK]);
+        dev.schedule_read(io, I2C_M_RD, 0x11, &[]);
+        dev.schedule_read(CMD_GET_STATUS, I2C_M_RD, 0, &[STATUS_ADDRESS_ACK]);
+        dev.schedule_read(io, I2C_M_RD, 0x12, &[]);
+        dev.schedule_read(CMD_GET_STATUS, I2C_M_RD, 0, &[STATUS_ADDRESS_NAK]);
+        assert_eq!(
+            scan(&dev, &TransferOptions::default(), 0x10..=0x12),
+            Ok(vec![0x11])
+        );
+    }
+
+    #[test]
+    fn test_scan_fast() {
+        let dev = MockConnection::new();
+        let options = TransferOptions::default();
+        dev.fail_next_write(rusb::Error::Pipe);
+        assert_eq!(scan_fast(&dev, &options, 0x10..=0x10), Ok(vec![]));
+        dev.schedule_read(CMD_GET_STATUS, I2C_M_RD, 0, &[STATUS_ADDRESS_ACK]);
+        dev.schedule_read(CMD_GET_STATUS, I2C_M_RD, 0, &[STATUS_ADDRESS_NAK]);
+        dev.expect_write(CMD_I2C_IO | CMD_I2C_BEGIN | CMD_I2C_END, 0, 0x11, &[]);
+        dev.expect_write(CMD_I2C_IO | CMD_I2C_BEGIN | CMD_I2C_END, 0, 0x12, &[]);
+        assert_eq!(scan_fast(&dev, &options, 0x11..=0x12), Ok(vec![0x11]));
+        dev.verify();
+    }
+
+    #[test]
+    fn test_scan_fast_options() {
+        let dev = MockConnection::new();
+        let options = TransferOptions {
+            reserved_address_policy: ReservedAddressPolicy::Reject,
+            ..Default::default()
+        };
+        assert_eq!(
+            scan_fast(&dev, &options, 0x00..=0x10),
+            Err(Error::Usb(rusb::Error::InvalidParam))
+        );
+
+        // the second probe of 0x10 is acknowledged
+        let options = TransferOptions {
+            retries: 1,
+            timeout: Duration::from_millis(50),
+            ..Default::default()
+        };
+        dev.schedule_read(CMD_GET_STATUS, I2C_M_RD, 0, &[STATUS_ADDRESS_NAK]);
+        dev.schedule_read(CMD_GET_STATUS, I2C_M_RD, 0, &[STATUS_ADDRESS_ACK]);
+        dev.expect_write(CMD_I2C_IO | CMD_I2C_BEGIN | CMD_I2C_END, 0, 0x10, &[]);
+        dev.expect_write(CMD_I2C_IO | CMD_I2C_BEGIN | CMD_I2C_END, 0, 0x10, &[]);
+        assert_eq!(scan_fast(&dev, &options, 0x10..=0x10), Ok(vec![0x10]));
+        dev.verify();
+        assert_eq!(
+            dev.timeouts.borrow()[0],
+            (
+                CMD_I2C_IO | CMD_I2C_BEGIN | CMD_I2C_END,
+                Duration::from_millis(50)
+            )
+        );
+    }
+
+    #[test]
+    fn test_scan_detailed() {
+        let dev = MockConnection::new();
+        let io = CMD_I2C_IO | CMD_I2C_BEGIN | CMD_I2C_END;
+        dev.schedule_read(io, I2C_M_RD, 0x10, &[]);
+        dev.schedule_read(CMD_GET_STATUS, I2C_M_RD, 0, &[STATUS_ADDRESS_ACK]);
+        dev.schedule_read(io, I2C_M_RD, 0x11, &[]);
+        dev.schedule_read(CMD_GET_STATUS, I2C_M_RD, 0, &[STATUS_ADDRESS_NAK]);
+        // nothing scheduled for 0x12 so the mock returns an I/O error
+        assert_eq!(
+            scan_detailed(&dev, &TransferOptions::default(), 0x10..=0x12),
+            vec![
+                (0x10, ScanResult::Present),
+                (0x11, ScanResult::Absent),
+                (0x12, ScanResult::Error(Error::Usb(rusb::Error::Io))),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_transfer_detailed_failed_index() {
+        let dev = MockConnection::new();
+        dev.schedule_read(CMD_GET_STATUS, I2C_M_RD, 0, &[STATUS_IDLE]);
+        dev.schedule_read(CMD_GET_STATUS, I2C_M_RD, 0, &[STATUS_ADDRESS_NAK]);
+        let mut msgs = [
+            Message::Write {
+                address: 0x50,
+                data: &[0x11],
+                flags: WriteFlags::empty(),
+            },
+            Message::Write {
+                address: 0x51,
+                data: &[0x22],
+                flags: WriteFlags::empty(),
+            },
+            Message::Write {
+                address: 0x52,
+                data: &[0x33],
+                flags: WriteFlags::empty(),
+            },
+        ];
+        assert_eq!(
+            transfer_detailed(&dev, &TransferOptions::default(), &mut msgs),
+            Err(TransferError {
+                failed_index: 1,
+                source: Error::Nack
+            })
+        );
+        assert!(dev.pop_write(CMD_I2C_IO, 0, 0x51, &[0x22]));
+        assert!(dev.pop_write(CMD_I2C_IO | CMD_I2C_BEGIN, 0, 0x50, &[0x11]));
         assert!(!dev.has_writes(), "no more write I2C transactions expected");
     }
+
+    #[test]
+    fn test_raw_control() {
+        let dev = MockConnection::new();
+        dev.schedule_read(0x42, 0x1234, 0x5678, &[0xaa, 0xbb]);
+        dev.expect_write(0x43, 0x1111, 0x2222, &[0xcc]);
+        let mut buf = [0u8; 4];
+        assert_eq!(
+            raw_control_read(&dev, 0x42, 0x1234, 0x5678, &mut buf),
+            Ok(2)
+        );
+        assert_eq!(buf[..2], [0xaa, 0xbb]);
+        assert_eq!(
+            raw_control_write(&dev, 0x43, 0x1111, 0x2222, &[0xcc]),
+            Ok(1)
+        );
+        dev.verify();
+    }
+
+    #[test]
+    fn test_over_reported_length() {
+        let dev = OverReporting;
+        let mut buf = [0u8; 4];
+        assert_eq!(
+            raw_control_read(&dev, 0x42, 0, 0, &mut buf),
+            Err(Error::Usb(rusb::Error::Overflow))
+        );
+        let options = TransferOptions {
+            max_read_len: 2,
+            ..Default::default()
+        };
+        assert_eq!(
+            transfer(
+                &dev,
+                &options,
+                &mut [Message::Read {
+                    address: 0x50,
+                    data: &mut buf,
+                    flags: ReadFlags::empty(),
+                }]
+            ),
+            Err(Error::Usb(rusb::Error::Overflow))
+        );
+        assert_eq!(get_status(&dev), Err(Error::Usb(rusb::Error::Overflow)));
+    }
+
+    #[test]
+    fn test_set_delay() {
+        let dev = MockConnection::new();
+        set_delay(&dev, 10).unwrap();
+        assert!(dev.pop_write(CMD_SET_DELAY, 10, 0, &[]));
+        assert!(!dev.has_writes(), "no more write transactions expected");
+    }
+
+    #[test]
+    fn test_set_delay_zero() {
+        let dev = MockConnection::new();
+        assert_eq!(
+            set_delay(&dev, 0),
+            Err(Error::Usb(rusb::Error::InvalidParam))
+        );
+        assert!(!dev.has_writes(), "invalid delay should not be sent");
+    }
+
+    #[test]
+    fn test_delay_for_frequency() {
+        assert_eq!(delay_for_frequency(50_000), Ok(10));
+        assert_eq!(delay_for_frequency(100_000), Ok(5));
+        assert_eq!(delay_for_frequency(400_000), Ok(2)); // rounded up, actual clock is 250kHz
+        assert_eq!(delay_for_frequency(500_000), Ok(1));
+        assert_eq!(
+            delay_for_frequency(1),
+            Err(Error::Usb(rusb::Error::InvalidParam))
+        );
+        assert_eq!(
+            delay_for_frequency(0),
+            Err(Error::Usb(rusb::Error::InvalidParam))
+        );
+    }
 }
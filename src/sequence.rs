@@ -0,0 +1,138 @@
+use i2c::{BulkTransfer, Message, ReadFlags, WriteFlags};
+use rusb::UsbContext;
+
+use crate::{Error, I2c, Result};
+
+enum Step {
+    Read {
+        address: u16,
+        len: usize,
+        flags: ReadFlags,
+    },
+    Write {
+        address: u16,
+        data: Vec<u8>,
+        flags: WriteFlags,
+    },
+}
+
+/// Sequence of reads and writes performed as a single transfer, created with
+/// [`I2c::raw_sequence`]. Steps added with `read` and `write` begin with a (repeated) start
+/// condition and address while the `continue_*` ones carry on right after the previous step
+/// without one, which requires protocol mangling support.
+pub struct RawSequence<'a, T: UsbContext> {
+    i2c: &'a mut I2c<T>,
+    steps: Vec<Step>,
+    error: Option<Error>,
+}
+
+impl<'a, T: UsbContext> RawSequence<'a, T> {
+    pub(crate) fn new(i2c: &'a mut I2c<T>) -> Self {
+        Self {
+            i2c,
+            steps: Vec::new(),
+            error: None,
+        }
+    }
+
+    /// Returns the address of the last step or records an error if there is none.
+    fn previous_address(&mut self) -> Option<u16> {
+        let address = match self.steps.last() {
+            Some(Step::Read { address, .. } | Step::Write { address, .. }) => Some(*address),
+            None => None,
+        };
+        if address.is_none() {
+            self.error.get_or_insert(rusb::Error::InvalidParam.into());
+        }
+        address
+    }
+
+    pub fn read(&mut self, address: u16, len: usize) -> &mut Self {
+        self.steps.push(Step::Read {
+            address,
+            len,
+            flags: ReadFlags::empty(),
+        });
+        self
+    }
+
+    pub fn write(&mut self, address: u16, data: &[u8]) -> &mut Self {
+        self.steps.push(Step::Write {
+            address,
+            data: data.to_vec(),
+            flags: WriteFlags::empty(),
+        });
+        self
+    }
+
+    /// Reads `len` more bytes from the peripheral of the previous step without a repeated start.
+    pub fn continue_read(&mut self, len: usize) -> &mut Self {
+        if !self.i2c.supported_flags().0.contains(ReadFlags::NO_START) {
+            self.error.get_or_insert(rusb::Error::NotSupported.into());
+        }
+        if let Some(address) = self.previous_address() {
+            self.steps.push(Step::Read {
+                address,
+                len,
+                flags: ReadFlags::NO_START,
+            });
+        }
+        self
+    }
+
+    /// Writes `data` to the peripheral of the previous step without a repeated start.
+    pub fn continue_write(&mut self, data: &[u8]) -> &mut Self {
+        if !self.i2c.supported_flags().1.contains(WriteFlags::NO_START) {
+            self.error.get_or_insert(rusb::Error::NotSupported.into());
+        }
+        if let Some(address) = self.previous_address() {
+            self.steps.push(Step::Write {
+                address,
+                data: data.to_vec(),
+                flags: WriteFlags::NO_START,
+            });
+        }
+        self
+    }
+
+    /// Performs the transfer and returns the data of each read step in order. Fails without
+    /// sending anything if a step needs flags the device doesn't support (`NotSupported`) or a
+    /// `continue_*` step has no previous step (`InvalidParam`).
+    pub fn run(&mut self) -> Result<Vec<Vec<u8>>> {
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+        let mut buffers: Vec<Vec<u8>> = self
+            .steps
+            .iter()
+            .filter_map(|step| match step {
+                Step::Read { len, .. } => Some(vec![0u8; *len]),
+                Step::Write { .. } => None,
+            })
+            .collect();
+        let mut buffers_iter = buffers.iter_mut();
+        let mut messages: Vec<Message> = self
+            .steps
+            .iter()
+            .map(|step| match step {
+                Step::Read { address, flags, .. } => Message::Read {
+                    address: *address,
+                    data: buffers_iter.next().unwrap(),
+                    flags: *flags,
+                },
+                Step::Write {
+                    address,
+                    data,
+                    flags,
+                } => Message::Write {
+                    address: *address,
+                    data,
+                    flags: *flags,
+                },
+            })
+            .collect();
+        self.i2c.i2c_transfer(&mut messages)?;
+        drop(messages);
+        Ok(buffers)
+    }
+}
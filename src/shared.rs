@@ -0,0 +1,67 @@
+use i2c::{BulkTransfer, Message};
+use rusb::UsbContext;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use crate::{I2c, Result};
+
+/// Handle to an [`I2c`] that can be cloned and used from multiple threads.
+///
+/// Every method locks the device for the duration of a single transfer, so messages of one
+/// transfer are never interleaved with messages from other threads. Nothing is guaranteed across
+/// separate calls; use [`SharedI2c::lock`] to perform several operations without interruption.
+/// Since the slave address set through [`i2c::Address`] is shared by all users, the methods here
+/// take the address explicitly instead.
+///
+/// If a thread panics while holding the lock, the device is still usable by the other threads.
+pub struct SharedI2c<T: UsbContext> {
+    inner: Arc<Mutex<I2c<T>>>,
+}
+
+impl<T: UsbContext> Clone for SharedI2c<T> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<T: UsbContext> SharedI2c<T> {
+    pub fn new(i2c: I2c<T>) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(i2c)),
+        }
+    }
+
+    /// Locks the device for exclusive use until the returned guard is dropped.
+    pub fn lock(&self) -> MutexGuard<'_, I2c<T>> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Performs the same transfer as [`i2c::BulkTransfer::i2c_transfer`].
+    pub fn transfer(&self, messages: &mut [Message]) -> Result<()> {
+        self.lock().i2c_transfer(messages)
+    }
+
+    /// Reads `buf.len()` bytes from the peripheral at `addr`.
+    pub fn read(&self, addr: u16, buf: &mut [u8]) -> Result<()> {
+        self.transfer(&mut [Message::Read {
+            address: addr,
+            data: buf,
+            flags: Default::default(),
+        }])
+    }
+
+    /// Writes `data` to the peripheral at `addr`.
+    pub fn write(&self, addr: u16, data: &[u8]) -> Result<()> {
+        self.transfer(&mut [Message::Write {
+            address: addr,
+            data,
+            flags: Default::default(),
+        }])
+    }
+
+    /// See [`I2c::write_read`].
+    pub fn write_read(&self, addr: u16, write: &[u8], read: &mut [u8]) -> Result<()> {
+        self.lock().write_read(addr, write, read)
+    }
+}
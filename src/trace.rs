@@ -0,0 +1,283 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+use crate::{protocol, Connection};
+
+/// Direction of a traced control transfer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceDirection {
+    /// From the adapter to the host.
+    In,
+    /// From the host to the adapter.
+    Out,
+}
+
+/// USB control transfer passed to the hook set with [`crate::I2c::set_trace_hook`]. For I2C I/O
+/// commands, `value` contains the message flags and `index` the I2C address.
+#[derive(Debug)]
+pub struct TraceEvent<'a> {
+    pub direction: TraceDirection,
+    /// The i2c-tiny-usb command.
+    pub request: u8,
+    pub value: u16,
+    pub index: u16,
+    /// Data sent or, for successful reads, received.
+    pub data: &'a [u8],
+    /// Number of bytes transferred or the USB error.
+    pub result: rusb::Result<usize>,
+}
+
+pub type TraceHook = Box<dyn Fn(&TraceEvent) + Send>;
+
+/// Durations of the control transfers of one command, see [`TimingStats`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CommandTiming {
+    pub count: u32,
+    pub min: Duration,
+    pub max: Duration,
+    pub total: Duration,
+}
+
+impl CommandTiming {
+    /// Returns the average duration of a transfer.
+    pub fn average(&self) -> Duration {
+        self.total / self.count.max(1)
+    }
+
+    fn add(&mut self, duration: Duration) {
+        self.count += 1;
+        self.min = self.min.min(duration);
+        self.max = self.max.max(duration);
+        self.total += duration;
+    }
+}
+
+/// Durations of the control transfers measured since [`crate::I2c::enable_timing`] was called,
+/// keyed by the i2c-tiny-usb command. I2C I/O commands are kept apart by their start and stop
+/// bits. Failed transfers are included.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TimingStats {
+    pub commands: BTreeMap<u8, CommandTiming>,
+}
+
+impl TimingStats {
+    fn add(&mut self, request: u8, duration: Duration) {
+        self.commands
+            .entry(request)
+            .or_insert(CommandTiming {
+                count: 0,
+                min: Duration::MAX,
+                max: Duration::ZERO,
+                total: Duration::ZERO,
+            })
+            .add(duration);
+    }
+}
+
+/// Connection calling a [`TraceHook`] after each control transfer and recording its duration in
+/// `timing`. If `read_only` is set, only the requests that don't change the state of the adapter
+/// are passed to `inner` and others fail with `rusb::Error::Access`.
+pub(crate) struct Traced<'a, C: Connection> {
+    pub inner: &'a C,
+    pub hook: Option<&'a TraceHook>,
+    pub timing: Option<&'a RefCell<TimingStats>>,
+    pub read_only: bool,
+}
+
+impl<C: Connection> Connection for Traced<'_, C> {
+    fn read_control(
+        &self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        buf: &mut [u8],
+        timeout: Duration,
+    ) -> rusb::Result<usize> {
+        if self.read_only && !protocol::is_passive_request(request) {
+            return Err(rusb::Error::Access);
+        }
+        let start = Instant::now();
+        let result = self
+            .inner
+            .read_control(request_type, request, value, index, buf, timeout);
+        if let Some(timing) = self.timing {
+            timing.borrow_mut().add(request, start.elapsed());
+        }
+        if let Some(hook) = self.hook {
+            // a misbehaving connection may report more than it could have read, which is caught
+            // later by the length checks in the protocol code and mustn't panic here
+            let len = (*result.as_ref().unwrap_or(&0)).min(buf.len());
+            hook(&TraceEvent {
+                direction: TraceDirection::In,
+                request,
+                value,
+                index,
+                data: &buf[..len],
+                result,
+            });
+        }
+        result
+    }
+
+    fn write_control(
+        &self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        buf: &[u8],
+        timeout: Duration,
+    ) -> rusb::Result<usize> {
+        if self.read_only {
+            return Err(rusb::Error::Access);
+        }
+        let start = Instant::now();
+        let result = self
+            .inner
+            .write_control(request_type, request, value, index, buf, timeout);
+        if let Some(timing) = self.timing {
+            timing.borrow_mut().add(request, start.elapsed());
+        }
+        if let Some(hook) = self.hook {
+            hook(&TraceEvent {
+                direction: TraceDirection::Out,
+                request,
+                value,
+                index,
+                data: buf,
+                result,
+            });
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::mock::{
+        MockConnection, OverReporting, REQUEST_TYPE_IN, REQUEST_TYPE_OUT,
+    };
+    use crate::protocol::constants::{CMD_ECHO, CMD_I2C_IO, CMD_SET_DELAY};
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_traced() {
+        let dev = MockConnection::new();
+        dev.schedule_read(1, 2, 3, &[0xaa]);
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let hook: TraceHook = {
+            let events = events.clone();
+            Box::new(move |e: &TraceEvent| {
+                let mut events = events.lock().unwrap();
+                events.push((e.direction, e.request, e.data.to_vec(), e.result));
+            })
+        };
+        let traced = Traced {
+            inner: &dev,
+            hook: Some(&hook),
+            timing: None,
+            read_only: false,
+        };
+        let mut buf = [0u8; 2];
+        assert_eq!(
+            traced.read_control(REQUEST_TYPE_IN, 1, 2, 3, &mut buf, Duration::ZERO),
+            Ok(1)
+        );
+        assert_eq!(
+            traced.write_control(REQUEST_TYPE_OUT, 4, 5, 6, &[0xbb], Duration::ZERO),
+            Ok(1)
+        );
+        assert_eq!(
+            *events.lock().unwrap(),
+            [
+                (TraceDirection::In, 1, vec![0xaa], Ok(1)),
+                (TraceDirection::Out, 4, vec![0xbb], Ok(1)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_traced_over_reported_length() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let hook: TraceHook = {
+            let events = events.clone();
+            Box::new(move |e: &TraceEvent| events.lock().unwrap().push(e.data.to_vec()))
+        };
+        let traced = Traced {
+            inner: &OverReporting,
+            hook: Some(&hook),
+            timing: None,
+            read_only: false,
+        };
+        let mut buf = [0u8; 2];
+        assert_eq!(
+            traced.read_control(REQUEST_TYPE_IN, 1, 2, 3, &mut buf, Duration::ZERO),
+            Ok(3)
+        );
+        assert_eq!(*events.lock().unwrap(), [vec![0xaa, 0xaa]]);
+    }
+
+    #[test]
+    fn test_timing() {
+        let dev = MockConnection::new();
+        dev.schedule_read(1, 0, 0, &[0xaa]);
+        dev.schedule_read(1, 0, 0, &[0xaa]);
+        let timing = RefCell::new(TimingStats::default());
+        let traced = Traced {
+            inner: &dev,
+            hook: None,
+            timing: Some(&timing),
+            read_only: false,
+        };
+        let mut buf = [0u8; 1];
+        traced
+            .read_control(REQUEST_TYPE_IN, 1, 0, 0, &mut buf, Duration::ZERO)
+            .unwrap();
+        traced
+            .read_control(REQUEST_TYPE_IN, 1, 0, 0, &mut buf, Duration::ZERO)
+            .unwrap();
+        traced
+            .write_control(REQUEST_TYPE_OUT, 2, 0, 0, &[], Duration::ZERO)
+            .unwrap();
+
+        let stats = timing.into_inner();
+        assert_eq!(stats.commands.keys().copied().collect::<Vec<_>>(), [1, 2]);
+        let echo = stats.commands[&1];
+        assert_eq!(echo.count, 2);
+        assert!(echo.min <= echo.average() && echo.average() <= echo.max);
+        assert_eq!(stats.commands[&2].count, 1);
+    }
+
+    #[test]
+    fn test_read_only() {
+        let dev = MockConnection::new();
+        dev.schedule_read(CMD_ECHO, 0x1234, 0, &[0x34, 0x12]);
+        let traced = Traced {
+            inner: &dev,
+            hook: None,
+            timing: None,
+            read_only: true,
+        };
+        assert_eq!(protocol::echo(&traced, 0x1234), Ok(0x1234));
+        let mut buf = [0u8; 1];
+        assert_eq!(
+            traced.read_control(
+                REQUEST_TYPE_IN,
+                CMD_I2C_IO,
+                0,
+                0x50,
+                &mut buf,
+                Duration::ZERO
+            ),
+            Err(rusb::Error::Access)
+        );
+        assert_eq!(
+            traced.write_control(REQUEST_TYPE_OUT, CMD_SET_DELAY, 10, 0, &[], Duration::ZERO),
+            Err(rusb::Error::Access)
+        );
+        dev.verify();
+    }
+}